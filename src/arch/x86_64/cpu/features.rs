@@ -0,0 +1,50 @@
+//! CPU feature detection through the `cpuid` instruction.
+
+use core::arch::x86_64::__cpuid;
+
+/// Gets whether the CPU is able to map 1 GiB pages.
+pub fn has_1gib_pages() -> bool {
+    // CPUID.80000001H:EDX.Page1GB [bit 26]
+    let result = unsafe { __cpuid(0x8000_0001) };
+    result.edx & (1 << 26) != 0
+}
+
+/// Gets whether the CPU supports global pages, which are kept in the TLB when CR3 is written.
+pub fn has_global_pages() -> bool {
+    // CPUID.01H:EDX.PGE [bit 13]
+    let result = unsafe { __cpuid(0x1) };
+    result.edx & (1 << 13) != 0
+}
+
+/// Gets whether the CPU supports the page attribute table, which chooses the memory type of each
+/// page.
+pub fn has_pat() -> bool {
+    // CPUID.01H:EDX.PAT [bit 16]
+    let result = unsafe { __cpuid(0x1) };
+    result.edx & (1 << 16) != 0
+}
+
+/// Gets whether the CPU supports process-context identifiers, which tag TLB entries with the
+/// address space that they belong to.
+pub fn has_pcid() -> bool {
+    // CPUID.01H:ECX.PCID [bit 17]
+    let result = unsafe { __cpuid(0x1) };
+    result.ecx & (1 << 17) != 0
+}
+
+/// Gets whether the CPU supports the `invpcid` instruction.
+pub fn has_invpcid() -> bool {
+    // CPUID.(EAX=07H,ECX=0H):EBX.INVPCID [bit 10]
+    max_leaf() >= 0x7 && unsafe { __cpuid(0x7) }.ebx & (1 << 10) != 0
+}
+
+/// Gets whether the CPU supports five-level paging, with 57-bit virtual addresses.
+pub fn has_la57() -> bool {
+    // CPUID.(EAX=07H,ECX=0H):ECX.LA57 [bit 16]
+    max_leaf() >= 0x7 && unsafe { __cpuid(0x7) }.ecx & (1 << 16) != 0
+}
+
+/// Gets the highest basic leaf that `cpuid` supports.
+fn max_leaf() -> u32 {
+    unsafe { __cpuid(0x0) }.eax
+}
@@ -1 +1,2 @@
 pub mod apic;
+pub mod features;
@@ -3,6 +3,7 @@ use x86_64::{
         idt::{
             Idt,
             ExceptionStackFrame,
+            PageFaultErrorCode,
         },
         gdt::SegmentSelector,
         tss::TaskStateSegment,
@@ -14,7 +15,7 @@ use x86_64::{
     VirtualAddress,
 };
 use spin::Once;
-use memory::{MemoryController, FrameAllocator};
+use memory::{self, MemoryController, FrameAllocator, PageFault};
 
 mod gdt;
 
@@ -31,19 +32,26 @@ fn setup_idt() -> Idt {
     unsafe {
         idt.double_fault.set_handler_fn(double_fault_handler)
             .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+        // page faults get their own stack, so that stack overflows can be reported
+        idt.page_fault.set_handler_fn(page_fault_handler)
+            .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
     }
 
     idt
 }
 
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const PAGE_FAULT_IST_INDEX: usize = 1;
 
 pub fn init(memory_controller: &mut MemoryController<impl FrameAllocator>) {
     let double_fault_stack = memory_controller.alloc_stack(1)
         .expect("Could not allocate a stack for the double fault handler");
+    let page_fault_stack = memory_controller.alloc_stack(4)
+        .expect("Could not allocate a stack for the page fault handler");
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(double_fault_stack.top());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(page_fault_stack.top());
         tss
     });
 
@@ -71,6 +79,37 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFra
     vgaprintln!("{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame,
+                                              error_code: PageFaultErrorCode) {
+    use x86_64::registers::control_regs::cr2;
+
+    let fault = PageFault {
+        address: cr2().0,
+        present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+        write: error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        user: error_code.contains(PageFaultErrorCode::USER_MODE),
+        instruction_fetch: error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+    };
+    if let Some(guard) = memory::find_guard_page(fault.address) {
+        vgaprintln!("= KERNEL STACK OVERFLOW");
+        vgaprintln!("Stack: {} ({:#x} - {:#x})", guard.name(), guard.stack_bottom(), guard.stack_top());
+        vgaprintln!("Guard page: {:#x}", guard.start_address());
+        vgaprintln!("Address: {:#x}", fault.address);
+        vgaprintln!("{:#?}", stack_frame);
+        loop {}
+    }
+    if memory::handle_page_fault(&fault) {
+        return;
+    }
+
+    vgaprintln!("= PAGE FAULT EXCEPTION");
+    vgaprintln!("Details:");
+    vgaprintln!("Address: {:#x}", fault.address);
+    vgaprintln!("Error code: {:?}", error_code);
+    vgaprintln!("{:#?}", stack_frame);
+    loop {}
+}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
     vgaprintln!("= DOUBLE FAULT EXCEPTION");
     vgaprintln!("Details:");
@@ -3,6 +3,8 @@ pub mod interrupt;
 pub mod stack;
 pub mod cpu;
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 /// Enables various features on the EFER register.
 ///
 /// The features which are enabled are:
@@ -21,6 +23,36 @@ pub fn enable_efer_features() {
     }
 }
 
+/// The page attribute table that `init_pat` programs.
+///
+/// Entries 0-3 keep their power-on values (write-back, write-through, uncached-minus and
+/// uncacheable), so pages that don't set the PAT bit behave the same either way. Entry 4 is
+/// write-combining, and entries 5-7 repeat 1-3.
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+/// Whether the page attribute table has been programmed.
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Programs the page attribute table, if the CPU supports it, so that pages can be mapped
+/// write-combining.
+///
+/// This must be called before anything is mapped with the PAT bit set.
+pub fn init_pat() {
+    use x86_64::registers::msr::{IA32_PAT, wrmsr};
+
+    if cpu::features::has_pat() {
+        unsafe {
+            wrmsr(IA32_PAT, PAT_VALUE);
+        }
+        PAT_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Gets whether the page attribute table has been programmed by `init_pat`.
+pub fn pat_enabled() -> bool {
+    PAT_ENABLED.load(Ordering::SeqCst)
+}
+
 /// Enables write protection of pages in kernel mode.
 ///
 /// By default, when in kernel mode, x86 ignores the write-protect bit on pages. This disables this
@@ -32,3 +64,86 @@ pub fn enable_kernel_write_protect() {
         cr0_write(cr0() | Cr0::WRITE_PROTECT);
     }
 }
+
+/// CR4.PGE, which enables global pages.
+pub const CR4_PGE: u64 = 1 << 7;
+
+/// CR4.LA57, which enables five-level paging.
+const CR4_LA57: u64 = 1 << 12;
+
+/// CR4.PCIDE, which enables process-context identifiers.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// Reads the CR4 register.
+///
+/// Unlike `x86_64::registers::control_regs::cr4`, this keeps bits that the `Cr4` flags don't
+/// know about.
+pub fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov %cr4, $0" : "=r"(value));
+    }
+    value
+}
+
+/// Writes the CR4 register.
+pub unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory");
+}
+
+/// Enables global pages, if the CPU supports them.
+///
+/// Global pages stay in the TLB when CR3 is written, so kernel mappings survive address space
+/// switches.
+pub fn enable_global_pages() {
+    if cpu::features::has_global_pages() {
+        unsafe {
+            write_cr4(read_cr4() | CR4_PGE);
+        }
+    }
+}
+
+/// Gets whether global pages are enabled.
+pub fn global_pages_enabled() -> bool {
+    read_cr4() & CR4_PGE != 0
+}
+
+/// Enables process-context identifiers, if the CPU supports both them and `invpcid`.
+///
+/// This must be called while the PCID in CR3 is 0, which is the case at boot.
+pub fn enable_pcid() {
+    use arch::x86_64::cpu::features::{has_pcid, has_invpcid};
+
+    if has_pcid() && has_invpcid() {
+        unsafe {
+            write_cr4(read_cr4() | CR4_PCIDE);
+        }
+    }
+}
+
+/// Gets whether process-context identifiers are enabled.
+pub fn pcid_enabled() -> bool {
+    read_cr4() & CR4_PCIDE != 0
+}
+
+/// Gets whether five-level paging is enabled.
+///
+/// It can only be enabled before paging is, so the boot code decides this based on
+/// `cpu::features::has_la57`.
+pub fn la57_enabled() -> bool {
+    read_cr4() & CR4_LA57 != 0
+}
+
+/// Moves onto another stack and calls `f` on it.
+///
+/// Nothing on the old stack is used afterwards, so it can be freed once `f` is running.
+///
+/// # Arguments
+/// `stack` - the stack to move onto, which must stay mapped for as long as `f` runs.
+/// `f` - the function to call on the new stack.
+pub unsafe fn switch_stack(stack: &stack::Stack, f: extern fn() -> !) -> ! {
+    extern "C" {
+        fn _switch_stack(stack_top: usize, f: extern fn() -> !) -> !;
+    }
+    _switch_stack(stack.top(), f)
+}
@@ -1,6 +1,6 @@
 use memory::{
     Page, ActivePageTable, PAGE_SIZE, EntryFlags,
-    FrameAllocator,
+    FrameAllocator, GuardPage, register_guard_page,
 };
 
 pub struct Stack {
@@ -48,12 +48,14 @@ impl<I: Iterator<Item=Page> + Clone> StackAllocator<I> {
         };
 
         match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
+            (Some(guard), Some(start), Some(end)) => {
                 self.range = range;
                 for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, EntryFlags::WRITABLE, allocator);
+                    active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NOEXEC, allocator);
                 }
                 let stack_top = end.start_address() + PAGE_SIZE;
+                register_guard_page(GuardPage::new(guard.start_address(), start.start_address(), stack_top,
+                                                   "kernel stack"));
                 Some(Stack::new(stack_top, start.start_address()))
             },
             _ => None,
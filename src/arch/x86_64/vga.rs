@@ -2,12 +2,13 @@ use core::ptr::Unique;
 use core::fmt;
 use volatile::Volatile;
 use spin::Mutex;
+use memory::map::KERNEL_VGA_BUFFER;
 
 /// The global writer for the x86 VGA buffer.
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     col: 0,
     color: ColorCode::new(Color::White, Color::Black),
-    buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new_unchecked(KERNEL_VGA_BUFFER as *mut _) },
 });
 
 #[macro_export]
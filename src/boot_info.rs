@@ -0,0 +1,553 @@
+//! The information that the bootloader hands to the kernel.
+//!
+//! Everything that the kernel needs is copied out of the multiboot2 structure by `init`, before
+//! memory is set up. Nothing else reads the multiboot2 structure, so its frames are handed out like
+//! any other free memory afterwards.
+
+// TODO(arch) multiboot2 is only used to boot on x86
+
+use core::{fmt, ptr, str};
+use core::sync::atomic::{AtomicBool, Ordering};
+use multiboot2::{BootInformation, MemoryMapTag};
+use memory::{PhysicalAddress, MemoryMapArea, MemoryAreaType};
+
+/// The largest number of memory map areas that can be kept.
+///
+/// UEFI firmware often reports well over 64 areas. Neighbouring areas of the same type are merged,
+/// and if there are still too many, areas that aren't usable are dropped to make room.
+pub const MAX_MEMORY_AREAS: usize = 256;
+
+/// The largest number of allocated ELF sections that can be kept. Any sections past this are
+/// merged into the one before them.
+const MAX_ELF_SECTIONS: usize = 64;
+
+/// The largest number of boot modules that can be kept. Any modules past this are ignored, and
+/// their memory is treated as free.
+const MAX_MODULES: usize = 16;
+
+/// The longest string that is kept, in bytes. Longer strings are cut short.
+const MAX_STRING_LEN: usize = 256;
+
+/// The multiboot2 tag that describes the framebuffer.
+const FRAMEBUFFER_TAG: u32 = 8;
+
+/// The multiboot2 tag that holds a copy of the ACPI 1.0 RSDP.
+const RSDP_V1_TAG: u32 = 14;
+
+/// The multiboot2 tag that holds a copy of the ACPI 2.0+ RSDP.
+const RSDP_V2_TAG: u32 = 15;
+
+/// The multiboot2 tag that ends the tag list.
+const END_TAG: u32 = 0;
+
+/// The kernel's copy of the boot information.
+///
+/// This is filled in place by `init`, since it is too large to be built on the boot stack and moved
+/// into place.
+static mut BOOT_INFO: BootInfo = BootInfo::empty();
+
+/// Whether `BOOT_INFO` has been filled in.
+static BOOT_INFO_READY: AtomicBool = AtomicBool::new(false);
+
+/// Copies the boot information out of the multiboot2 structure.
+///
+/// This must be called exactly once, before anything allocates frames, since the frames that hold
+/// the multiboot2 structure are not reserved.
+pub fn init(multiboot: &BootInformation) -> &'static BootInfo {
+    assert!(!BOOT_INFO_READY.load(Ordering::SeqCst), "Boot information has already been copied");
+    unsafe {
+        BOOT_INFO.copy_from(multiboot);
+        BOOT_INFO_READY.store(true, Ordering::SeqCst);
+        &BOOT_INFO
+    }
+}
+
+/// Gets the boot information.
+///
+/// Panics if `init` has not been called yet.
+pub fn boot_info() -> &'static BootInfo {
+    assert!(BOOT_INFO_READY.load(Ordering::SeqCst), "Boot information has not been copied yet");
+    unsafe { &BOOT_INFO }
+}
+
+bitflags! {
+    /// The flags of an ELF section.
+    pub struct ElfSectionFlags: u64 {
+        const WRITABLE = 0x1;
+        const ALLOCATED = 0x2;
+        const EXECUTABLE = 0x4;
+    }
+}
+
+/// A string that was copied out of the boot information.
+#[derive(Clone, Copy)]
+pub struct BootString {
+    bytes: [u8; MAX_STRING_LEN],
+    len: usize,
+}
+
+impl BootString {
+    const fn empty() -> Self {
+        BootString { bytes: [0; MAX_STRING_LEN], len: 0 }
+    }
+
+    /// Copies a string, cutting it short at a character boundary if it is longer than
+    /// `MAX_STRING_LEN` bytes.
+    fn new(s: &str) -> Self {
+        let mut len = s.len().min(MAX_STRING_LEN);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut string = BootString::empty();
+        string.bytes[.. len].copy_from_slice(&s.as_bytes()[.. len]);
+        string.len = len;
+        string
+    }
+
+    pub fn as_str(&self) -> &str {
+        // this was copied from a &str, cut at a character boundary
+        unsafe { str::from_utf8_unchecked(&self.bytes[.. self.len]) }
+    }
+}
+
+impl fmt::Debug for BootString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for BootString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An allocated section of the kernel's ELF image.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfSection {
+    name: BootString,
+    start: usize,
+    end: usize,
+    flags: ElfSectionFlags,
+}
+
+impl ElfSection {
+    const EMPTY: ElfSection = ElfSection {
+        name: BootString::empty(),
+        start: 0,
+        end: 0,
+        flags: ElfSectionFlags { bits: 0 },
+    };
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Gets the address that this section is linked at.
+    pub fn start_address(&self) -> usize {
+        self.start
+    }
+
+    /// Gets the address that this section ends at (exclusive).
+    pub fn end_address(&self) -> usize {
+        self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn flags(&self) -> ElfSectionFlags {
+        self.flags
+    }
+
+    pub fn is_allocated(&self) -> bool {
+        self.flags.contains(ElfSectionFlags::ALLOCATED)
+    }
+}
+
+/// A module that was loaded by the bootloader, before it is mapped into kernel memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleInfo {
+    start: PhysicalAddress,
+    end: PhysicalAddress,
+    cmdline: BootString,
+}
+
+impl ModuleInfo {
+    const EMPTY: ModuleInfo = ModuleInfo { start: 0, end: 0, cmdline: BootString::empty() };
+
+    /// Gets the physical address that this module starts at.
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.start
+    }
+
+    /// Gets the physical address that this module ends at (exclusive).
+    pub fn end_address(&self) -> PhysicalAddress {
+        self.end
+    }
+
+    /// Gets the command line that was passed along with this module.
+    pub fn cmdline(&self) -> &str {
+        self.cmdline.as_str()
+    }
+}
+
+/// How the pixels of a framebuffer are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferType {
+    Indexed,
+    Rgb,
+    Text,
+    Unknown(u8),
+}
+
+impl From<u8> for FramebufferType {
+    fn from(typ: u8) -> Self {
+        match typ {
+            0 => FramebufferType::Indexed,
+            1 => FramebufferType::Rgb,
+            2 => FramebufferType::Text,
+            typ => FramebufferType::Unknown(typ),
+        }
+    }
+}
+
+/// The framebuffer that the bootloader set up.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    /// The physical address of the framebuffer.
+    pub address: PhysicalAddress,
+    /// The number of bytes in each row.
+    pub pitch: u32,
+    /// The width, in pixels (or characters, for text mode).
+    pub width: u32,
+    /// The height, in pixels (or characters, for text mode).
+    pub height: u32,
+    /// The number of bits in each pixel.
+    pub bpp: u8,
+    pub typ: FramebufferType,
+}
+
+impl Framebuffer {
+    /// Gets the size of the framebuffer, in bytes.
+    pub fn size(&self) -> usize {
+        self.pitch as usize * self.height as usize
+    }
+}
+
+/// The ACPI root system description pointer, which leads to the rest of the ACPI tables.
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    /// The ACPI revision; 0 for ACPI 1.0, 2 for ACPI 2.0 and up.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// The physical address of the RSDT.
+    pub rsdt_address: PhysicalAddress,
+    /// The physical address of the XSDT, which is only available since ACPI 2.0.
+    pub xsdt_address: Option<PhysicalAddress>,
+}
+
+/// Everything that the kernel knows from the bootloader.
+pub struct BootInfo {
+    memory_areas: [MemoryMapArea; MAX_MEMORY_AREAS],
+    memory_area_count: usize,
+    elf_sections: [ElfSection; MAX_ELF_SECTIONS],
+    elf_section_count: usize,
+    modules: [ModuleInfo; MAX_MODULES],
+    module_count: usize,
+    command_line: BootString,
+    framebuffer: Option<Framebuffer>,
+    rsdp: Option<Rsdp>,
+}
+
+impl BootInfo {
+    const fn empty() -> Self {
+        BootInfo {
+            memory_areas: [MemoryMapArea { start: 0, size: 0, typ: MemoryAreaType::Reserved }; MAX_MEMORY_AREAS],
+            memory_area_count: 0,
+            elf_sections: [ElfSection::EMPTY; MAX_ELF_SECTIONS],
+            elf_section_count: 0,
+            modules: [ModuleInfo::EMPTY; MAX_MODULES],
+            module_count: 0,
+            command_line: BootString::empty(),
+            framebuffer: None,
+            rsdp: None,
+        }
+    }
+
+    /// Gets every area of the memory map, regardless of type.
+    pub fn memory_areas(&self) -> &[MemoryMapArea] {
+        &self.memory_areas[.. self.memory_area_count]
+    }
+
+    /// Gets the areas of the memory map that are available for use.
+    pub fn usable_memory_areas<'a>(&'a self) -> impl Iterator<Item=&'a MemoryMapArea> + Clone {
+        self.memory_areas().iter()
+            .filter(|area| area.typ == MemoryAreaType::Available)
+    }
+
+    /// Gets the allocated sections of the kernel's ELF image.
+    pub fn elf_sections(&self) -> &[ElfSection] {
+        &self.elf_sections[.. self.elf_section_count]
+    }
+
+    /// Gets every module that was loaded by the bootloader.
+    pub fn modules(&self) -> &[ModuleInfo] {
+        &self.modules[.. self.module_count]
+    }
+
+    /// Gets the kernel's command line, which is empty if the bootloader didn't pass one.
+    pub fn command_line(&self) -> &str {
+        self.command_line.as_str()
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.framebuffer
+    }
+
+    pub fn rsdp(&self) -> Option<Rsdp> {
+        self.rsdp
+    }
+
+    /// Adds an area of the memory map, merging it into the area before it if they touch and have
+    /// the same type.
+    ///
+    /// If there is no room left, an area that isn't usable is dropped instead, with a warning.
+    fn push_memory_area(&mut self, area: MemoryMapArea) {
+        if let Some(last) = self.memory_areas[.. self.memory_area_count].last_mut() {
+            if last.typ == area.typ && last.end() == area.start {
+                last.size += area.size;
+                return;
+            }
+        }
+        if self.memory_area_count == MAX_MEMORY_AREAS {
+            if area.typ != MemoryAreaType::Available {
+                vgaprintln!("Too many memory map areas, ignoring {:?} area at {:#x} - {:#x}",
+                            area.typ, area.start, area.end());
+                return;
+            }
+            let unusable = self.memory_areas().iter()
+                .rposition(|stored| stored.typ != MemoryAreaType::Available);
+            match unusable {
+                Some(index) => {
+                    let dropped = self.memory_areas[index];
+                    vgaprintln!("Too many memory map areas, ignoring {:?} area at {:#x} - {:#x}",
+                                dropped.typ, dropped.start, dropped.end());
+                    for i in index .. self.memory_area_count - 1 {
+                        self.memory_areas[i] = self.memory_areas[i + 1];
+                    }
+                    self.memory_area_count -= 1;
+                },
+                None => {
+                    vgaprintln!("Too many memory map areas, ignoring usable memory at {:#x} - {:#x}",
+                                area.start, area.end());
+                    return;
+                },
+            }
+        }
+        self.memory_areas[self.memory_area_count] = area;
+        self.memory_area_count += 1;
+    }
+
+    /// Adds an allocated ELF section.
+    ///
+    /// If there is no room left, the section is merged into the one before it, which then covers
+    /// both with the permissions of either, as long as they are in the same half of the address
+    /// space. Otherwise it is dropped, with a warning.
+    fn push_elf_section(&mut self, section: ElfSection) {
+        use memory::map::KERNEL_BASE;
+
+        if self.elf_section_count < MAX_ELF_SECTIONS {
+            self.elf_sections[self.elf_section_count] = section;
+            self.elf_section_count += 1;
+            return;
+        }
+        let last = &mut self.elf_sections[MAX_ELF_SECTIONS - 1];
+        if (last.start < KERNEL_BASE) == (section.start < KERNEL_BASE) {
+            vgaprintln!("Too many ELF sections, merging {} into {}", section.name(), last.name());
+            last.start = last.start.min(section.start);
+            last.end = last.end.max(section.end);
+            last.flags |= section.flags;
+        } else {
+            vgaprintln!("Too many ELF sections, ignoring {}", section.name());
+        }
+    }
+
+    /// Copies everything that the kernel needs out of the multiboot2 structure.
+    fn copy_from(&mut self, multiboot: &BootInformation) {
+        let memory_map = multiboot.memory_map_tag()
+            .expect("Could not find memory map tag in multiboot2 data");
+        for area in MemoryMapIter::new(memory_map) {
+            self.push_memory_area(area);
+        }
+
+        let elf_sections = multiboot.elf_sections_tag()
+            .expect("Could not find ELF sections tag in multiboot2 data");
+        for section in elf_sections.sections().filter(|section| section.is_allocated()) {
+            self.push_elf_section(ElfSection {
+                name: BootString::new(section.name()),
+                start: section.start_address() as usize,
+                end: section.end_address() as usize,
+                flags: ElfSectionFlags::from_bits_truncate(section.flags().bits()),
+            });
+        }
+
+        for module in multiboot.module_tags() {
+            if self.module_count == MAX_MODULES {
+                vgaprintln!("Too many boot modules, ignoring {}", module.name());
+                continue;
+            }
+            self.modules[self.module_count] = ModuleInfo {
+                start: module.start_address() as usize,
+                end: module.end_address() as usize,
+                cmdline: BootString::new(module.name()),
+            };
+            self.module_count += 1;
+        }
+
+        if let Some(tag) = multiboot.command_line_tag() {
+            self.command_line = BootString::new(tag.command_line());
+        }
+
+        // the multiboot2 crate doesn't know about these tags, so they're read by hand
+        for (typ, address) in TagIter::new(multiboot) {
+            match typ {
+                FRAMEBUFFER_TAG => self.framebuffer = Some(unsafe { read_framebuffer(address) }),
+                // an ACPI 2.0 RSDP is preferred over an ACPI 1.0 one
+                RSDP_V1_TAG if self.rsdp.is_none() => self.rsdp = unsafe { read_rsdp(address, false) },
+                RSDP_V2_TAG => self.rsdp = unsafe { read_rsdp(address, true) },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// Reads a framebuffer tag.
+///
+/// The tag has an 8 byte header, followed by the address (u64), the pitch, width and height (u32
+/// each), the bits per pixel (u8) and the framebuffer type (u8).
+unsafe fn read_framebuffer(tag_address: usize) -> Framebuffer {
+    Framebuffer {
+        address: ptr::read_unaligned((tag_address + 8) as *const u64) as usize,
+        pitch: ptr::read_unaligned((tag_address + 16) as *const u32),
+        width: ptr::read_unaligned((tag_address + 20) as *const u32),
+        height: ptr::read_unaligned((tag_address + 24) as *const u32),
+        bpp: ptr::read((tag_address + 28) as *const u8),
+        typ: FramebufferType::from(ptr::read((tag_address + 29) as *const u8)),
+    }
+}
+
+/// Reads an RSDP tag, which holds a copy of the RSDP after its 8 byte header.
+///
+/// The RSDP starts with the signature (8 bytes), the checksum (u8), the OEM ID (6 bytes), the
+/// revision (u8) and the RSDT address (u32). ACPI 2.0 adds the length (u32) and the XSDT address
+/// (u64), followed by a checksum over the whole structure. `None` is returned if the checksum is
+/// wrong.
+unsafe fn read_rsdp(tag_address: usize, extended: bool) -> Option<Rsdp> {
+    let rsdp = tag_address + 8;
+    let checksum_len = if extended {
+        ptr::read_unaligned((rsdp + 20) as *const u32) as usize
+    } else {
+        20
+    };
+    let checksum = (0 .. checksum_len)
+        .fold(0u8, |sum, offset| sum.wrapping_add(ptr::read((rsdp + offset) as *const u8)));
+    if checksum != 0 {
+        vgaprintln!("Ignoring RSDP with a bad checksum");
+        return None;
+    }
+    Some(Rsdp {
+        revision: ptr::read((rsdp + 15) as *const u8),
+        oem_id: ptr::read((rsdp + 9) as *const [u8; 6]),
+        rsdt_address: ptr::read_unaligned((rsdp + 16) as *const u32) as usize,
+        xsdt_address: if extended {
+            Some(ptr::read_unaligned((rsdp + 24) as *const u64) as usize)
+        } else {
+            None
+        },
+    })
+}
+
+/// An iterator over the raw tags of a multiboot2 structure, giving the type and address of each.
+struct TagIter {
+    current: usize,
+    end: usize,
+}
+
+impl TagIter {
+    /// Creates an iterator over every tag of the given multiboot2 structure.
+    ///
+    /// The structure starts with an 8 byte header, followed by tags that each start with their
+    /// type and size (u32 each), and are padded to 8 bytes.
+    fn new(multiboot: &BootInformation) -> Self {
+        TagIter {
+            current: multiboot.start_address() + 8,
+            end: multiboot.end_address(),
+        }
+    }
+}
+
+impl Iterator for TagIter {
+    type Item = (u32, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current + 8 > self.end {
+            return None;
+        }
+        let (typ, size) = unsafe {
+            (ptr::read(self.current as *const u32), ptr::read((self.current + 4) as *const u32))
+        };
+        if typ == END_TAG || size < 8 {
+            return None;
+        }
+        let address = self.current;
+        self.current = (self.current + size as usize + 7) & !7;
+        Some((typ, address))
+    }
+}
+
+/// An iterator over every area in a multiboot2 memory map, regardless of type.
+struct MemoryMapIter {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+impl MemoryMapIter {
+    /// Creates an iterator over all areas of the given memory map tag.
+    ///
+    /// This reads the tag according to the multiboot2 spec: a 16 byte header with the tag's size
+    /// and entry size, followed by entries which start with the base address (u64), the length
+    /// (u64) and the type (u32).
+    fn new(tag: &MemoryMapTag) -> Self {
+        let tag_address = tag as *const _ as usize;
+        let (size, entry_size) = unsafe {
+            (ptr::read((tag_address + 4) as *const u32), ptr::read((tag_address + 8) as *const u32))
+        };
+        MemoryMapIter {
+            current: tag_address + 16,
+            end: tag_address + size as usize,
+            entry_size: entry_size as usize,
+        }
+    }
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = MemoryMapArea;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entry_size == 0 || self.current + self.entry_size > self.end {
+            return None;
+        }
+        let area = unsafe {
+            MemoryMapArea {
+                start: ptr::read(self.current as *const u64) as usize,
+                size: ptr::read((self.current + 8) as *const u64) as usize,
+                typ: MemoryAreaType::from(ptr::read((self.current + 16) as *const u32)),
+            }
+        };
+        self.current += self.entry_size;
+        Some(area)
+    }
+}
@@ -3,6 +3,7 @@
 #![feature(alloc, allocator_api, global_allocator)]
 #![feature(abi_x86_interrupt)]
 #![feature(nll)]
+#![feature(asm)]
 #![no_std]
 
 extern crate rlibc;
@@ -17,6 +18,7 @@ extern crate x86_64;
 extern crate bit_field;
 
 #[macro_use] pub mod arch;
+pub mod boot_info;
 pub mod memory;
 
 use core::panic::PanicInfo;
@@ -34,17 +36,33 @@ pub static GLOBAL_ALLOCATOR: BuddyAllocator = memory::KERNEL_HEAP_ALLOCATOR;
 #[cfg(not(test))]
 #[no_mangle]
 pub extern fn kmain(boot_info_addr: usize) -> ! {
-    let boot_info = unsafe { multiboot2::load(boot_info_addr) };
+    // nothing may allocate frames before the boot information is copied out
+    let boot_info = boot_info::init(unsafe { &multiboot2::load(boot_info_addr) });
     // TODO(arch) this is x86_64 specific
     arch::x86_64::enable_efer_features();
     arch::x86_64::enable_kernel_write_protect();
+    arch::x86_64::enable_global_pages();
+    arch::x86_64::enable_pcid();
+    arch::x86_64::init_pat();
 
     vgaprintln!("Initialize memory");
-    let mut memory_controller = memory::init(boot_info);
+    let memory_controller = memory::init(boot_info);
     vgaprintln!("Initialize interrupts");
-    arch::x86_64::interrupt::init(&mut memory_controller);
+    arch::x86_64::interrupt::init(&mut *memory_controller.lock());
     //x86_64::instructions::interrupts::int3();
 
+    // the boot stack is in .bss.early, so move off of it before it is reclaimed
+    let stack = memory_controller.lock().alloc_stack(16)
+        .expect("Could not allocate the kernel stack");
+    unsafe { arch::x86_64::switch_stack(&stack, kmain_stack) }
+}
+
+/// The rest of the kernel entrypoint, which runs on a stack allocated by `kmain`.
+#[cfg(not(test))]
+extern fn kmain_stack() -> ! {
+    vgaprintln!("Reclaim boot memory");
+    memory::controller().lock().reclaim_boot_memory();
+
     vgaprintln!();
     vgaprintln!("================================================================================");
     vgaprintln!();
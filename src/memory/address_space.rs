@@ -0,0 +1,286 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use memory::{
+    PAGE_SIZE, Frame, FrameAllocator, FrameInfo, FrameOwner, Page, ActivePageTable, InactivePageTable, EntryFlags,
+    MapError, Table, TableLevel4, ENTRY_COUNT, kernel_p4_index, release_user_half, tlb, is_user_address,
+    map::USER_END,
+};
+
+/// The ID that the next address space is given.
+static NEXT_ADDRESS_SPACE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The most page tables of dropped address spaces that can wait to be freed at once.
+const MAX_DEFERRED_TABLES: usize = 64;
+
+/// The page tables of dropped address spaces that couldn't be freed right away, because the memory
+/// controller was locked.
+///
+/// This is only ever locked for as long as it takes to add or take out a table.
+static DEFERRED_TABLES: Mutex<DeferredTables> = Mutex::new(DeferredTables::new());
+
+/// An address space, such as a process's.
+///
+/// The user half of an address space belongs to it alone, while the kernel half is shared with
+/// every other address space. When an address space is dropped, all of its user page tables and
+/// the frames behind them are freed.
+///
+/// Address spaces are edited through the physical map, so it must be set up.
+///
+/// If the CPU supports PCIDs, each address space tags its TLB entries with its own PCID, so
+/// switching between address spaces doesn't flush the TLB.
+pub struct AddressSpace {
+    id: usize,
+    table: InactivePageTable,
+    pcid: Option<u16>,
+}
+
+impl AddressSpace {
+    /// Creates a new address space with an empty user half, sharing the kernel half of
+    /// `active_table`.
+    pub fn new<A>(active_table: &ActivePageTable, allocator: &mut A) -> Result<Self, MapError>
+        where A: FrameAllocator
+    {
+        let p4_frame = allocator.alloc()
+            .ok_or(MapError::OutOfFrames)?;
+        let mut address_space = AddressSpace::from_table(InactivePageTable::new_direct(p4_frame, allocator)?);
+        {
+            let p4 = address_space.p4();
+            // the recursive entry was already set by InactivePageTable::new_direct
+            for index in kernel_p4_index() .. ENTRY_COUNT - 1 {
+                p4[index] = active_table.p4()[index].clone();
+            }
+        }
+        Ok(address_space)
+    }
+
+    /// Takes ownership of an inactive page table, such as one that was made by
+    /// `Mapper::clone_copy_on_write`.
+    ///
+    /// The kernel half of the table must already be shared with the active table.
+    pub fn from_table(table: InactivePageTable) -> Self {
+        AddressSpace {
+            id: NEXT_ADDRESS_SPACE_ID.fetch_add(1, Ordering::SeqCst),
+            table,
+            pcid: tlb::alloc_pcid(),
+        }
+    }
+
+    /// Gets the ID of this address space, which is unique for as long as the kernel runs.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Gets the PCID that this address space's TLB entries are tagged with, if it has one.
+    pub fn pcid(&self) -> Option<u16> {
+        self.pcid
+    }
+
+    /// Gets whether this address space is the active one.
+    pub fn is_active(&self) -> bool {
+        // TODO(arch) abstract away x86_64 calls
+        use x86_64::registers::control_regs;
+
+        Frame::containing_address(control_regs::cr3().0 as usize) == *self.table.root_frame()
+    }
+
+    /// Makes this address space the active one, returning the previously active page table.
+    pub fn activate(&self, active_table: &mut ActivePageTable) -> InactivePageTable {
+        let table = InactivePageTable {
+            p4_frame: self.table.p4_frame.clone(),
+            p5_frame: self.table.p5_frame.as_ref().map(Frame::clone),
+        };
+        match self.pcid {
+            Some(pcid) => active_table.switch_tagged(table, pcid),
+            None => active_table.switch(table),
+        }
+    }
+
+    /// Maps a user page to a newly allocated frame, which is owned by this address space.
+    ///
+    /// `USER` is added to `flags`.
+    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert_user_page(page);
+        let frame = allocator.alloc()
+            .ok_or(MapError::OutOfFrames)?;
+        if let Some(info) = FrameInfo::get(&frame) {
+            info.set_owner(FrameOwner::AddressSpace(self.id));
+        }
+        if let Err(error) = self.map_to(page, frame.clone(), flags, allocator) {
+            allocator.dealloc(frame);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Maps a user page to a given frame.
+    ///
+    /// `USER` is added to `flags`. If the frame's references are counted, this mapping takes one
+    /// of them, which is given back when the page is unmapped.
+    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert_user_page(page);
+        // nothing can be cached for a page that wasn't mapped, so the TLB is left alone
+        self.table.with(|mapper| mapper.try_map_to(page, frame, flags | EntryFlags::USER, allocator))
+    }
+
+    /// Unmaps a user page, releasing this mapping's reference to its frame.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert_user_page(page);
+        let frame = self.table.with(|mapper| mapper.try_unmap(page, allocator))?;
+        if FrameInfo::get(&frame).map(|info| info.ref_count() > 0).unwrap_or(false) {
+            frame.dec_ref(allocator);
+        }
+        // the mapper only flushed the page from the active address space
+        self.invalidate_if_inactive();
+        Ok(())
+    }
+
+    /// Changes the protection flags of every user page in the given range.
+    pub fn protect<A>(&mut self, start: Page, end: Page, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert_user_page(start);
+        assert_user_page(end);
+        // with five-level paging, the range must not span the kernel's addresses under P5 entry 0
+        assert!((start.start_address() < USER_END) == (end.start_address() < USER_END),
+                "Range {:#x} - {:#x} is not in user space", start.start_address(), end.start_address());
+        let (start, end) = (start.start_address(), end.start_address() + PAGE_SIZE);
+        let result = self.table.with(|mapper| mapper.protect(start, end, flags | EntryFlags::USER, allocator));
+        // even if protecting failed, some of the pages may have been changed
+        self.invalidate_if_inactive();
+        result
+    }
+
+    /// Flushes this address space's TLB entries if it isn't the active one, since its pages were
+    /// only flushed from the active address space.
+    fn invalidate_if_inactive(&self) {
+        if let Some(pcid) = self.pcid {
+            if !self.is_active() {
+                tlb::invalidate_pcid(pcid);
+            }
+        }
+    }
+
+    /// Gets this address space's P4 table through the physical map.
+    fn p4(&mut self) -> &mut Table<TableLevel4> {
+        let address = self.table.p4_frame.virtual_address()
+            .expect("Address spaces can only be edited through the physical map");
+        unsafe { &mut *(address as *mut Table<TableLevel4>) }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every user page table and frame of this address space, along with its P4 table.
+    ///
+    /// If the memory controller is locked, e.g. because the address space is dropped while it is
+    /// held, the page tables are freed later by `free_deferred_tables` instead. The address space
+    /// must not be active.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Attempted to drop the active address space #{}", self.id);
+        if let Some(pcid) = self.pcid.take() {
+            tlb::free_pcid(pcid);
+        }
+        let table = InactivePageTable {
+            p4_frame: self.table.p4_frame.clone(),
+            p5_frame: self.table.p5_frame.take(),
+        };
+        match ::memory::controller().try_lock() {
+            Some(mut controller) => {
+                release_table(table, &mut controller.frame_allocator);
+                free_deferred_tables(&mut controller.frame_allocator);
+            },
+            None => {
+                if !DEFERRED_TABLES.lock().push(&table) {
+                    vgaprintln!("Too many dropped address spaces, leaking the page tables of #{}", self.id);
+                }
+            },
+        }
+    }
+}
+
+/// Page tables of dropped address spaces, kept as frame numbers so that nothing has to be
+/// allocated to hold on to them.
+struct DeferredTables {
+    /// The frame numbers of each P4 table and its P5 table, if it has one.
+    tables: [(usize, Option<usize>); MAX_DEFERRED_TABLES],
+    count: usize,
+}
+
+impl DeferredTables {
+    const fn new() -> Self {
+        DeferredTables {
+            tables: [(0, None); MAX_DEFERRED_TABLES],
+            count: 0,
+        }
+    }
+
+    /// Adds a page table, returning whether there was room for it.
+    fn push(&mut self, table: &InactivePageTable) -> bool {
+        if self.count == MAX_DEFERRED_TABLES {
+            return false;
+        }
+        let p5_number = table.p5_frame.as_ref().map(|frame| frame.number);
+        self.tables[self.count] = (table.p4_frame.number, p5_number);
+        self.count += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<InactivePageTable> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        let (p4_number, p5_number) = self.tables[self.count];
+        Some(InactivePageTable {
+            p4_frame: Frame { number: p4_number },
+            p5_frame: p5_number.map(|number| Frame { number }),
+        })
+    }
+}
+
+/// Frees the page tables of every address space that was dropped while the memory controller was
+/// locked.
+pub (in memory) fn free_deferred_tables<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    loop {
+        let table = match DEFERRED_TABLES.lock().pop() {
+            Some(table) => table,
+            None => break,
+        };
+        release_table(table, allocator);
+    }
+}
+
+/// Frees every user page table and frame of a page table, along with its P4 and P5 tables.
+fn release_table<A>(mut table: InactivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    release_user_half(&mut table, allocator);
+    table.dealloc_p5(allocator);
+    allocator.dealloc(table.p4_frame);
+}
+
+/// Makes sure that a page lies in the user half of the address space.
+fn assert_user_page(page: Page) {
+    assert!(is_user_address(page.start_address()), "Page {:#x} is not in user space", page.start_address());
+}
+
+/// Creates every P3 table of the kernel half up front.
+///
+/// Address spaces copy the kernel half of the P4 table when they are created, so its entries must
+/// never change afterwards.
+pub (in memory) fn init_kernel_tables<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    for index in kernel_p4_index() .. ENTRY_COUNT - 1 {
+        active_table.p4_mut().next_table_create(index, allocator);
+    }
+}
@@ -0,0 +1,293 @@
+use core::ptr;
+use spin::Mutex;
+use memory::{
+    ActivePageTable, EntryFlags, Frame, FrameAllocator, MapError, Page, PAGE_SIZE, VirtualAddress,
+    page_table_edit_in_progress,
+};
+
+/// A page fault, decoded from whatever the architecture reports.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The address that was accessed.
+    pub address: VirtualAddress,
+
+    /// Whether the page was present, i.e. the fault was caused by a protection violation.
+    pub present: bool,
+
+    /// Whether the access was a write.
+    pub write: bool,
+
+    /// Whether the access came from user mode.
+    pub user: bool,
+
+    /// Whether the access was an instruction fetch.
+    pub instruction_fetch: bool,
+}
+
+/// A range of virtual memory whose pages are backed by zeroed frames the first time they are
+/// touched.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: EntryFlags,
+}
+
+impl LazyRegion {
+    /// Gets the address that this region starts at.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Gets the address that this region ends at (exclusive).
+    pub fn end_address(&self) -> VirtualAddress {
+        self.end
+    }
+
+    /// Gets the flags that pages of this region are mapped with.
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    /// Gets whether this region contains the given address.
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    /// Gets whether the faulting access would be allowed once the page is mapped.
+    pub fn allows(&self, fault: &PageFault) -> bool {
+        !(fault.write && !self.flags.contains(EntryFlags::WRITABLE))
+            && !(fault.user && !self.flags.contains(EntryFlags::USER))
+            && !(fault.instruction_fetch && self.flags.contains(EntryFlags::NOEXEC))
+    }
+}
+
+/// The most lazily backed regions that can be registered at once.
+const MAX_LAZY_REGIONS: usize = 64;
+
+/// The number of frames that are set aside for lazily backed pages. Each fault takes one for the
+/// page, and possibly a few more for the page tables on the way to it.
+const LAZY_FRAME_RESERVE: usize = 16;
+
+/// The lazily backed regions and the frames that back them.
+///
+/// This is kept apart from the memory controller, so that lazy faults can still be handled while
+/// the controller is locked, e.g. when it touches a lazily backed page itself. It is only ever
+/// locked for short stretches that don't touch the heap, stacks or lazily backed memory, so a
+/// fault can never happen while it is held.
+static DEMAND_PAGER: Mutex<DemandPager> = Mutex::new(DemandPager::new());
+
+/// The registry of every lazily backed region.
+///
+/// The regions are kept in a fixed-size table, so looking them up or changing them never touches
+/// the heap.
+pub struct LazyRegions {
+    regions: [Option<LazyRegion>; MAX_LAZY_REGIONS],
+    count: usize,
+}
+
+impl LazyRegions {
+    pub const fn new() -> Self {
+        LazyRegions {
+            regions: [None; MAX_LAZY_REGIONS],
+            count: 0,
+        }
+    }
+
+    /// Registers a lazily backed region.
+    ///
+    /// # Arguments
+    /// `start` - the page-aligned address that the region starts at.
+    /// `end` - the page-aligned address that the region ends at (exclusive).
+    /// `flags` - the flags to map pages of the region with.
+    pub fn insert(&mut self, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags) {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+                "Lazy region {:#x} - {:#x} is not page-aligned", start, end);
+        assert!(self.iter().all(|region| end <= region.start || start >= region.end),
+                "Lazy region {:#x} - {:#x} overlaps with another lazy region", start, end);
+        assert!(self.count < MAX_LAZY_REGIONS,
+                "Too many lazy regions to register {:#x} - {:#x}", start, end);
+        self.regions[self.count] = Some(LazyRegion { start, end, flags });
+        self.count += 1;
+    }
+
+    /// Removes the region that starts at the given address, returning it if it was registered.
+    pub fn remove(&mut self, start: VirtualAddress) -> Option<LazyRegion> {
+        let index = self.iter().position(|region| region.start == start)?;
+        let region = self.regions[index].take();
+        self.count -= 1;
+        self.regions.swap(index, self.count);
+        region
+    }
+
+    /// Finds the region that contains the given address.
+    pub fn find(&self, address: VirtualAddress) -> Option<LazyRegion> {
+        self.iter()
+            .find(|region| region.contains(address))
+            .cloned()
+    }
+
+    /// Gets an iterator over every registered region.
+    fn iter<'a>(&'a self) -> impl Iterator<Item=&'a LazyRegion> + 'a {
+        self.regions[.. self.count].iter()
+            .map(|region| region.as_ref().unwrap())
+    }
+}
+
+/// Frames that are set aside so that lazily backed pages can be mapped without going through the
+/// memory controller's frame allocator.
+struct FrameReserve {
+    frames: [usize; LAZY_FRAME_RESERVE],
+    count: usize,
+}
+
+impl FrameReserve {
+    const fn new() -> Self {
+        FrameReserve {
+            frames: [0; LAZY_FRAME_RESERVE],
+            count: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == LAZY_FRAME_RESERVE
+    }
+
+    /// Gets how many frames are missing from the reserve.
+    fn missing(&self) -> usize {
+        LAZY_FRAME_RESERVE - self.count
+    }
+}
+
+impl FrameAllocator for FrameReserve {
+    fn alloc(&mut self) -> Option<Frame> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(Frame { number: self.frames[self.count] })
+    }
+
+    fn dealloc(&mut self, frame: Frame) {
+        // only frames that were just taken out are ever given back, so there is always room
+        assert!(!self.is_full(), "Lazy frame reserve is full, could not take back frame #{:#x}", frame.number);
+        self.frames[self.count] = frame.number;
+        self.count += 1;
+    }
+}
+
+/// Everything that is needed to handle a fault on a lazily backed page.
+struct DemandPager {
+    regions: LazyRegions,
+    frames: FrameReserve,
+}
+
+impl DemandPager {
+    const fn new() -> Self {
+        DemandPager {
+            regions: LazyRegions::new(),
+            frames: FrameReserve::new(),
+        }
+    }
+}
+
+/// Registers a lazily backed region.
+///
+/// See `LazyRegions::insert` for the arguments.
+pub (in memory) fn register_lazy_region(start: VirtualAddress, end: VirtualAddress, flags: EntryFlags) {
+    DEMAND_PAGER.lock().regions.insert(start, end, flags);
+}
+
+/// Removes the lazily backed region that starts at the given address, returning it if it was
+/// registered.
+pub (in memory) fn unregister_lazy_region(start: VirtualAddress) -> Option<LazyRegion> {
+    DEMAND_PAGER.lock().regions.remove(start)
+}
+
+/// Tops up the frames that are set aside for lazily backed pages.
+///
+/// The frames are allocated without holding the pager's lock, so that a fault taken by the
+/// allocator can still be handled.
+pub (in memory) fn refill_lazy_frames<A>(allocator: &mut A)
+    where A: FrameAllocator
+{
+    let missing = DEMAND_PAGER.lock().frames.missing();
+    let mut frames = FrameReserve::new();
+    for _ in 0 .. missing {
+        match allocator.alloc() {
+            Some(frame) => frames.dealloc(frame),
+            None => break,
+        }
+    }
+
+    {
+        let mut pager = DEMAND_PAGER.lock();
+        while !pager.frames.is_full() {
+            match frames.alloc() {
+                Some(frame) => pager.frames.dealloc(frame),
+                None => break,
+            }
+        }
+    }
+    // a fault may have used up fewer frames than were missing in the meantime
+    while let Some(frame) = frames.alloc() {
+        allocator.dealloc(frame);
+    }
+}
+
+/// Handles a page fault by mapping a zeroed frame, if the fault is the first touch of a page in a
+/// lazily backed region.
+///
+/// This doesn't need the memory controller, so it also works while the controller is locked. The
+/// frames, including the ones for any page tables on the way, come from the ones that were set
+/// aside by `refill_lazy_frames`.
+///
+/// Returns whether the fault was handled. Faults taken while the pager's lock is held can't be.
+pub (in memory) fn handle_lazy_fault(fault: &PageFault) -> bool {
+    let mut pager = match DEMAND_PAGER.try_lock() {
+        Some(pager) => pager,
+        None => {
+            vgaprintln!("Lazy region lock is held, could not handle fault at {:#x}", fault.address);
+            return false;
+        },
+    };
+    let DemandPager { ref regions, ref mut frames } = *pager;
+    let region = match regions.find(fault.address) {
+        Some(region) if region.allows(fault) => region,
+        _ => return false,
+    };
+
+    // whoever holds the controller's handle to the active table was interrupted by this fault, so
+    // this handle may only be used if that wasn't in the middle of an edit
+    assert!(!page_table_edit_in_progress(),
+            "Lazy page fault at {:#x} while page tables were being edited", fault.address);
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let page = Page::containing_address(fault.address);
+    let frame = match frames.alloc() {
+        Some(frame) => frame,
+        None => {
+            vgaprintln!("Lazy frame reserve ran dry, could not map {:#x}", page.start_address());
+            return false;
+        },
+    };
+    // the page has to be writable while it is zeroed
+    let flags = region.flags() | EntryFlags::WRITABLE;
+    if let Err(error) = active_table.try_map_to(page, frame.clone(), flags, frames) {
+        if error == MapError::OutOfFrames {
+            vgaprintln!("Lazy frame reserve ran dry, could not map {:#x}", page.start_address());
+        } else {
+            vgaprintln!("Could not map lazily backed page {:#x}: {}", page.start_address(), error);
+        }
+        frames.dealloc(frame);
+        return false;
+    }
+    unsafe {
+        ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE);
+    }
+    if !region.flags().contains(EntryFlags::WRITABLE) {
+        let start = page.start_address();
+        active_table.protect(start, start + PAGE_SIZE, region.flags(), frames)
+            .expect("Could not make lazily backed page read-only");
+    }
+    true
+}
@@ -0,0 +1,175 @@
+use memory::{Frame, FrameAllocator, MemoryMapArea, MemoryAreaType};
+
+/// The maximum number of extra ranges that may be reserved with `AreaFrameAllocator::reserve`.
+const MAX_RESERVED_RANGES: usize = 16;
+
+/// The maximum number of frames that `AreaFrameAllocator::dealloc` keeps track of.
+const MAX_FREED_FRAMES: usize = 64;
+
+/// A simple frame allocator.
+///
+/// This hands out frames in increasing order, so it is only used to bootstrap memory until the
+/// `BitmapFrameAllocator` is set up. Frames that are given back (like emptied page tables) are kept
+/// in a small free list, which is handed out first and taken over by the bitmap allocator.
+pub struct AreaFrameAllocator {
+    next_frame: Frame,
+    current_area: Option<&'static MemoryMapArea>,
+    areas: &'static [MemoryMapArea],
+    kernel_start: Frame,
+    kernel_end: Frame,
+    /// Extra inclusive ranges of frame numbers that must not be handed out.
+    reserved: [(usize, usize); MAX_RESERVED_RANGES],
+    reserved_count: usize,
+    /// Frame numbers that were given back with `dealloc`.
+    freed: [usize; MAX_FREED_FRAMES],
+    freed_count: usize,
+}
+
+impl AreaFrameAllocator {
+    /// Creates an allocator that hands out frames from the available areas of a memory map.
+    ///
+    /// The kernel's frames, from `kernel_start` to `kernel_end`, are never handed out.
+    pub fn new(areas: &'static [MemoryMapArea], kernel_start: usize, kernel_end: usize) -> Self {
+        let mut alloc = AreaFrameAllocator {
+            next_frame: Frame { number: 0 },
+            current_area: None,
+            areas,
+            kernel_start: Frame::containing_address(kernel_start),
+            kernel_end: Frame::containing_address(kernel_end),
+            reserved: [(0, 0); MAX_RESERVED_RANGES],
+            reserved_count: 0,
+            freed: [0; MAX_FREED_FRAMES],
+            freed_count: 0,
+        };
+        alloc.choose_next_area();
+        alloc
+    }
+
+    /// Reserves the physical range from `start` to `end` (exclusive), so it won't be handed out.
+    ///
+    /// Ranges that touch or overlap an already reserved range are merged with it. If there is no
+    /// room left for another range, it is merged with the closest one instead, so the frames in
+    /// between aren't handed out by this allocator either (nor by the bitmap allocator afterwards, if
+    /// they lie below `next_frame`).
+    ///
+    /// This must be called before any frames are allocated.
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        if end <= start {
+            return;
+        }
+        let (mut first, mut last) = (Frame::containing_address(start).number, Frame::containing_address(end - 1).number);
+
+        // merging two ranges can make the result touch a third one, so keep going until nothing changes
+        let mut index = 0;
+        while index < self.reserved_count {
+            let (other_first, other_last) = self.reserved[index];
+            if first <= other_last.saturating_add(1) && other_first <= last.saturating_add(1) {
+                first = first.min(other_first);
+                last = last.max(other_last);
+                self.reserved_count -= 1;
+                self.reserved[index] = self.reserved[self.reserved_count];
+                index = 0;
+            } else {
+                index += 1;
+            }
+        }
+
+        if self.reserved_count == MAX_RESERVED_RANGES {
+            let distance = |&(other_first, other_last): &(usize, usize)| {
+                if other_last < first { first - other_last } else { other_first - last }
+            };
+            let closest = (0 .. self.reserved_count)
+                .min_by_key(|&index| distance(&self.reserved[index]))
+                .unwrap();
+            vgaprintln!("Too many reserved ranges in AreaFrameAllocator, also reserving the gap to range #{}", closest);
+            let (other_first, other_last) = self.reserved[closest];
+            self.reserved[closest] = (first.min(other_first), last.max(other_last));
+        } else {
+            self.reserved[self.reserved_count] = (first, last);
+            self.reserved_count += 1;
+        }
+    }
+
+    /// Gets the last frame number of the reserved range that contains the given frame, if any.
+    fn reserved_end(&self, frame: &Frame) -> Option<usize> {
+        self.reserved[.. self.reserved_count].iter()
+            .find(|&&(start, end)| frame.number >= start && frame.number <= end)
+            .map(|&(_, end)| end)
+    }
+
+    /// Gets the next frame that this allocator will consider handing out.
+    ///
+    /// Every usable frame below this one has either been allocated, is reserved, or is in
+    /// `freed_frames`.
+    pub fn next_frame(&self) -> Frame {
+        self.next_frame.clone()
+    }
+
+    /// Gets the frames below `next_frame` that were given back and not handed out again.
+    pub fn freed_frames<'a>(&'a self) -> impl Iterator<Item=Frame> + 'a {
+        self.freed[.. self.freed_count].iter()
+            .map(|&number| Frame { number })
+    }
+
+    fn choose_next_area(&mut self) {
+        self.current_area = self.areas.iter()
+            .filter(|area| area.typ == MemoryAreaType::Available)
+            .filter(|area| Frame::containing_address(area.end() - 1) >= self.next_frame)
+            .min_by_key(|area| area.start);
+        if let Some(area) = self.current_area {
+            let area_start_frame = Frame::containing_address(area.start);
+            assert!(self.next_frame <= area_start_frame,
+                concat!("next_frame in AreaFrameAllocator was greater than the start frame for the desired area, ",
+                        "even though the area should have been filtered out"));
+            if self.next_frame < area_start_frame {
+                self.next_frame = area_start_frame;
+            }
+        }
+    }
+}
+
+impl FrameAllocator for AreaFrameAllocator {
+    fn alloc(&mut self) -> Option<Frame> {
+        if self.freed_count > 0 {
+            self.freed_count -= 1;
+            return Some(Frame { number: self.freed[self.freed_count] });
+        }
+        let area = self.current_area?;
+        let frame = Frame { number: self.next_frame.number };
+
+        // last frame of the current memory area
+        let area_last_frame = Frame::containing_address(area.end() - 1);
+
+        // last frame of the current area is too small for our next frame, so advance
+        if frame > area_last_frame {
+            self.choose_next_area();
+        } else if frame >= self.kernel_start && frame <= self.kernel_end {
+            self.next_frame = Frame { number: frame.number + 1 };
+        } else if let Some(reserved_end) = self.reserved_end(&frame) {
+            // reserved ranges (like boot modules) can be large, so skip over them all at once
+            self.next_frame.number = reserved_end + 1;
+        } else {
+            // if all the checks passed, then this frame is free and we can allocate it
+            self.next_frame.number += 1;
+            return Some(frame);
+        }
+        // since we're messing with the "next frame" and "current memory area", maybe the next
+        // allocation will succeed
+        self.alloc()
+    }
+
+    /// Gives a frame back, so that it can be handed out again.
+    ///
+    /// If the free list is full, the frame is leaked until the next boot; this only happens if a
+    /// lot of page tables are freed while the kernel is being remapped.
+    fn dealloc(&mut self, frame: Frame) {
+        assert!(frame < self.next_frame,
+                "Attempted to free frame #{:#x}, which was never allocated", frame.number);
+        if self.freed_count < MAX_FREED_FRAMES {
+            self.freed[self.freed_count] = frame.number;
+            self.freed_count += 1;
+        } else {
+            vgaprintln!("Boot frame allocator is leaking frame #{:#x}", frame.number);
+        }
+    }
+}
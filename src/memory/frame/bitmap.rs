@@ -0,0 +1,275 @@
+use core::{mem, slice, usize};
+use memory::{
+    PAGE_SIZE, Frame, FrameRange, FrameAllocator, ContiguousFrameAllocator, ZonedFrameAllocator, Zone,
+    FrameInfo, PhysicalAddress, VirtualAddress,
+};
+
+/// The number of frames that are tracked by a single word of the bitmap.
+const FRAMES_PER_WORD: usize = mem::size_of::<u64>() * 8;
+
+/// Bookkeeping for a single zone of the bitmap.
+#[derive(Debug, Clone, Copy)]
+struct ZoneState {
+    /// The first frame number in this zone.
+    start: usize,
+
+    /// The frame number that this zone ends at (exclusive).
+    end: usize,
+
+    /// The number of frames in this zone that are currently free.
+    free_count: usize,
+
+    /// The frame number that the next search for a free frame in this zone starts at.
+    ///
+    /// Every frame in this zone before this one is known to be used.
+    next_free: usize,
+}
+
+/// A frame allocator that keeps one bit for every physical frame.
+///
+/// A set bit means that the frame is in use (or doesn't exist), and a clear bit means that the
+/// frame is free for allocation. Frames can be freed and reused.
+///
+/// Frames are split up into zones (see `Zone`); unconstrained allocations are served from the
+/// highest zone that has free frames.
+pub struct BitmapFrameAllocator {
+    /// The bitmap itself, one bit per frame.
+    bitmap: &'static mut [u64],
+
+    /// The number of frames that this bitmap covers, starting with frame 0.
+    frame_count: usize,
+
+    /// Bookkeeping for each zone, indexed by `Zone::index`.
+    zones: [ZoneState; 3],
+}
+
+impl BitmapFrameAllocator {
+    /// Gets the number of bytes that a bitmap needs in order to track `frame_count` frames.
+    pub fn bitmap_size(frame_count: usize) -> usize {
+        (frame_count + FRAMES_PER_WORD - 1) / FRAMES_PER_WORD * mem::size_of::<u64>()
+    }
+
+    /// Creates a new bitmap frame allocator whose bitmap lives at the given address.
+    ///
+    /// Every frame starts out as used; usable memory must be handed to the allocator with
+    /// `free_range`.
+    ///
+    /// # Arguments
+    /// `bitmap_start` - the mapped, writable address that the bitmap is stored at. It must be at
+    ///                  least `bitmap_size(frame_count)` bytes long.
+    /// `frame_count` - the number of frames to track.
+    pub unsafe fn new(bitmap_start: VirtualAddress, frame_count: usize) -> Self {
+        let word_count = Self::bitmap_size(frame_count) / mem::size_of::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_start as *mut u64, word_count);
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let zone_state = |zone: Zone| {
+            let start = (zone.start_address() / PAGE_SIZE).min(frame_count);
+            let end = (zone.end_address() / PAGE_SIZE).min(frame_count);
+            ZoneState { start, end, free_count: 0, next_free: start, }
+        };
+        BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            zones: [zone_state(Zone::Dma), zone_state(Zone::Dma32), zone_state(Zone::Normal)],
+        }
+    }
+
+    /// Marks every frame in the given inclusive range as free.
+    ///
+    /// Frames that lie outside of the bitmap are ignored.
+    pub fn free_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            if frame.number < self.frame_count && self.is_used(&frame) {
+                self.set_used(&frame, false);
+            }
+        }
+    }
+
+    /// Marks every frame in the given inclusive range as used, so it will never be handed out.
+    ///
+    /// Frames that lie outside of the bitmap are ignored.
+    pub fn reserve_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            if frame.number < self.frame_count && !self.is_used(&frame) {
+                self.set_used(&frame, true);
+            }
+        }
+    }
+
+    /// Gets the number of frames that this allocator covers.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Gets the number of frames that are currently free.
+    pub fn free_count(&self) -> usize {
+        self.zones.iter()
+            .map(|zone| zone.free_count)
+            .sum()
+    }
+
+    /// Gets whether the given frame is free for allocation.
+    pub fn is_free(&self, frame: &Frame) -> bool {
+        frame.number < self.frame_count && !self.is_used(frame)
+    }
+
+    /// Gets the number of frames that are currently free in the given zone.
+    pub fn zone_free_count(&self, zone: Zone) -> usize {
+        self.zones[zone.index()].free_count
+    }
+
+    /// Finds the first free frame in the given range of frame numbers, if any.
+    fn first_free(&self, start: usize, end: usize) -> Option<usize> {
+        let mut number = start;
+        while number < end {
+            let (word, bit) = (number / FRAMES_PER_WORD, number % FRAMES_PER_WORD);
+            // skip over whole words at a time when they are entirely used
+            if bit == 0 && self.bitmap[word] == !0 {
+                number += FRAMES_PER_WORD;
+            } else if self.bitmap[word] & (1 << bit) == 0 {
+                return Some(number);
+            } else {
+                number += 1;
+            }
+        }
+        None
+    }
+
+    /// Finds the first used frame in the given range of frame numbers, if any.
+    fn first_used(&self, start: usize, end: usize) -> Option<usize> {
+        let mut number = start;
+        while number < end {
+            let (word, bit) = (number / FRAMES_PER_WORD, number % FRAMES_PER_WORD);
+            // skip over whole words at a time when they are entirely free
+            if bit == 0 && number + FRAMES_PER_WORD <= end && self.bitmap[word] == 0 {
+                number += FRAMES_PER_WORD;
+            } else if self.bitmap[word] & (1 << bit) != 0 {
+                return Some(number);
+            } else {
+                number += 1;
+            }
+        }
+        None
+    }
+
+    /// Allocates the first free frame of the given zone whose number is below `end`.
+    fn alloc_zone(&mut self, zone: Zone, end: usize) -> Option<Frame> {
+        let state = self.zones[zone.index()];
+        if state.free_count == 0 {
+            return None;
+        }
+        let end = end.min(state.end);
+        let number = self.first_free(state.next_free, end);
+        if end == state.end {
+            // the whole zone was searched, so everything up to here is used
+            self.zones[zone.index()].next_free = number.unwrap_or(end);
+        }
+
+        number.map(|number| {
+            let frame = Frame { number };
+            self.set_used(&frame, true);
+            if let Some(info) = FrameInfo::get(&frame) {
+                info.reset(1);
+            }
+            frame
+        })
+    }
+
+    fn is_used(&self, frame: &Frame) -> bool {
+        let (word, bit) = (frame.number / FRAMES_PER_WORD, frame.number % FRAMES_PER_WORD);
+        self.bitmap[word] & (1 << bit) != 0
+    }
+
+    fn set_used(&mut self, frame: &Frame, used: bool) {
+        let (word, bit) = (frame.number / FRAMES_PER_WORD, frame.number % FRAMES_PER_WORD);
+        let zone = &mut self.zones[Zone::containing_address(frame.start_address()).index()];
+        if used {
+            self.bitmap[word] |= 1 << bit;
+            zone.free_count -= 1;
+        } else {
+            self.bitmap[word] &= !(1 << bit);
+            zone.free_count += 1;
+            if frame.number < zone.next_free {
+                zone.next_free = frame.number;
+            }
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn alloc(&mut self) -> Option<Frame> {
+        self.alloc_below(usize::MAX)
+    }
+
+    fn dealloc(&mut self, frame: Frame) {
+        assert!(frame.number < self.frame_count,
+                "Attempted to free frame #{:#x}, which is outside of the bitmap", frame.number);
+        assert!(self.is_used(&frame), "Attempted to free frame #{:#x} twice", frame.number);
+        if let Some(info) = FrameInfo::get(&frame) {
+            assert!(info.ref_count() <= 1, "Attempted to free frame #{:#x}, which is still shared", frame.number);
+            info.reset(0);
+        }
+        self.set_used(&frame, false);
+    }
+}
+
+impl ZonedFrameAllocator for BitmapFrameAllocator {
+    fn alloc_in(&mut self, zone: Zone) -> Option<Frame> {
+        self.alloc_zone(zone, usize::MAX)
+    }
+
+    fn alloc_below(&mut self, limit: PhysicalAddress) -> Option<Frame> {
+        let end = limit / PAGE_SIZE;
+        // prefer high memory, so that low memory is left over for devices that need it
+        Zone::ALL.iter()
+            .rev()
+            .filter_map(|&zone| self.alloc_zone(zone, end))
+            .next()
+    }
+}
+
+impl ContiguousFrameAllocator for BitmapFrameAllocator {
+    fn alloc_contiguous_below(&mut self, count: usize, align: usize, limit: PhysicalAddress)
+        -> Option<FrameRange>
+    {
+        assert!(align.is_power_of_two(), "Contiguous frame alignment must be a power of 2 (got {:#x})", align);
+        if count == 0 || count > self.free_count() {
+            return None;
+        }
+
+        let align_frames = if align > PAGE_SIZE { align / PAGE_SIZE } else { 1 };
+        let round_up = |number: usize| (number + align_frames - 1) & !(align_frames - 1);
+        let end = (limit / PAGE_SIZE).min(self.frame_count);
+
+        // prefer high memory, but allow runs to cross over into the zone above
+        for zone in Zone::ALL.iter().rev() {
+            let state = self.zones[zone.index()];
+            let mut start = round_up(state.next_free);
+            while start < state.end && start + count <= end {
+                match self.first_used(start, start + count) {
+                    Some(used) => start = round_up(used + 1),
+                    None => {
+                        for number in start .. start + count {
+                            let frame = Frame { number };
+                            self.set_used(&frame, true);
+                            if let Some(info) = FrameInfo::get(&frame) {
+                                info.reset(1);
+                            }
+                        }
+                        return Some(FrameRange::new(Frame { number: start }, count));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn dealloc_contiguous(&mut self, range: FrameRange) {
+        for frame in range.frames() {
+            self.dealloc(frame);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use core::{mem, ptr, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use memory::{Frame, FrameAllocator, VirtualAddress};
+
+/// The metadata table for every physical frame, indexed by frame number.
+static FRAME_INFO: Once<&'static [FrameInfo]> = Once::new();
+
+bitflags! {
+    pub struct FrameFlags: usize {
+        /// The frame is not managed by the frame allocator (e.g. firmware or the kernel image).
+        const RESERVED      = 1 << 0;
+        /// The frame holds a page table.
+        const PAGE_TABLE    = 1 << 1;
+        /// The frame is mapped read-only in several places, and is copied on the first write.
+        const COPY_ON_WRITE = 1 << 2;
+    }
+}
+
+/// The owner of a physical frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    /// Nobody has claimed this frame.
+    Unowned,
+    /// The frame belongs to the kernel.
+    Kernel,
+    /// The frame belongs to the address space with the given ID.
+    AddressSpace(usize),
+}
+
+impl FrameOwner {
+    fn to_raw(&self) -> usize {
+        match *self {
+            FrameOwner::Unowned => 0,
+            FrameOwner::Kernel => 1,
+            FrameOwner::AddressSpace(id) => id + 2,
+        }
+    }
+
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            0 => FrameOwner::Unowned,
+            1 => FrameOwner::Kernel,
+            id => FrameOwner::AddressSpace(id - 2),
+        }
+    }
+}
+
+/// Metadata that is kept for every physical frame.
+///
+/// All fields are atomic, so that metadata may be updated through a shared reference.
+pub struct FrameInfo {
+    ref_count: AtomicUsize,
+    flags: AtomicUsize,
+    owner: AtomicUsize,
+}
+
+impl FrameInfo {
+    /// Gets the number of bytes that the metadata table needs in order to cover `frame_count`
+    /// frames.
+    pub fn table_size(frame_count: usize) -> usize {
+        frame_count * mem::size_of::<FrameInfo>()
+    }
+
+    /// Sets up the global metadata table.
+    ///
+    /// # Arguments
+    /// `table_start` - the mapped, writable address that the table is stored at. It must be at
+    ///                 least `table_size(frame_count)` bytes long.
+    /// `frame_count` - the number of frames to cover.
+    pub (in memory) unsafe fn init_table(table_start: VirtualAddress, frame_count: usize) {
+        let table = slice::from_raw_parts_mut(table_start as *mut FrameInfo, frame_count);
+        for info in table.iter_mut() {
+            ptr::write(info, FrameInfo {
+                ref_count: AtomicUsize::new(0),
+                flags: AtomicUsize::new(0),
+                owner: AtomicUsize::new(FrameOwner::Unowned.to_raw()),
+            });
+        }
+        FRAME_INFO.call_once(|| table);
+    }
+
+    /// Gets the metadata for the given frame, if the table has been set up and covers it.
+    pub fn get(frame: &Frame) -> Option<&'static FrameInfo> {
+        FRAME_INFO.try()
+            .and_then(|table| table.get(frame.number))
+    }
+
+    pub fn ref_count(&self) -> usize {
+        self.ref_count.load(Ordering::SeqCst)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::SeqCst))
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::SeqCst);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::SeqCst);
+    }
+
+    pub fn owner(&self) -> FrameOwner {
+        FrameOwner::from_raw(self.owner.load(Ordering::SeqCst))
+    }
+
+    pub fn set_owner(&self, owner: FrameOwner) {
+        self.owner.store(owner.to_raw(), Ordering::SeqCst);
+    }
+
+    /// Resets this frame's metadata, giving it the specified reference count.
+    ///
+    /// This is used by frame allocators when a frame is handed out or taken back.
+    pub (in memory) fn reset(&self, ref_count: usize) {
+        self.flags.store(0, Ordering::SeqCst);
+        self.owner.store(FrameOwner::Unowned.to_raw(), Ordering::SeqCst);
+        self.ref_count.store(ref_count, Ordering::SeqCst);
+    }
+}
+
+impl Frame {
+    /// Gets the metadata for this frame.
+    pub fn info(&self) -> &'static FrameInfo {
+        FrameInfo::get(self)
+            .expect("Frame metadata table is not set up or does not cover this frame")
+    }
+
+    /// Gets the number of references to this frame.
+    pub fn ref_count(&self) -> usize {
+        self.info().ref_count()
+    }
+
+    /// Adds a reference to this frame, returning the new reference count.
+    pub fn inc_ref(&self) -> usize {
+        let old = self.info().ref_count.fetch_add(1, Ordering::SeqCst);
+        assert!(old > 0, "Attempted to add a reference to free frame #{:#x}", self.number);
+        old + 1
+    }
+
+    /// Releases a reference to this frame, returning the new reference count.
+    ///
+    /// When the last reference is released, the frame is given back to the allocator.
+    pub fn dec_ref<A>(self, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        let old = self.info().ref_count.fetch_sub(1, Ordering::SeqCst);
+        assert!(old > 0, "Attempted to release a reference to free frame #{:#x}", self.number);
+        if old == 1 {
+            // put the count back, since the allocator expects to take back a single reference
+            self.info().ref_count.store(1, Ordering::SeqCst);
+            allocator.dealloc(self);
+        }
+        old - 1
+    }
+}
@@ -0,0 +1,103 @@
+use core::usize;
+use memory::PhysicalAddress;
+
+mod area;
+mod bitmap;
+mod info;
+mod zone;
+
+pub use self::area::*;
+pub use self::bitmap::*;
+pub use self::info::*;
+pub use self::zone::*;
+
+/// The default page size.
+///
+/// 4K pages are frequently the
+pub const PAGE_SIZE: usize = 4096;
+
+/// A physical memory frame that has been allocated.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame {
+    pub (in memory) number: usize,
+}
+
+impl Frame {
+    pub (in memory) fn containing_address(addr: usize) -> Self {
+        Frame { number: addr / PAGE_SIZE }
+    }
+
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.number * PAGE_SIZE
+    }
+
+    pub (in memory) fn clone(&self) -> Self {
+        Frame { number: self.number }
+    }
+
+    pub (in memory) fn range_inclusive(start: Frame, end: Frame) -> impl Iterator<Item=Frame> {
+        (start.number ..= end.number).map(|number| Frame { number })
+    }
+}
+
+pub trait FrameAllocator {
+    fn alloc(&mut self) -> Option<Frame>;
+    fn dealloc(&mut self, frame: Frame);
+}
+
+/// A run of physically contiguous frames.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrameRange {
+    start: Frame,
+    count: usize,
+}
+
+impl FrameRange {
+    pub (in memory) fn new(start: Frame, count: usize) -> Self {
+        FrameRange { start, count }
+    }
+
+    /// Gets the first frame of this range.
+    pub fn start(&self) -> &Frame {
+        &self.start
+    }
+
+    /// Gets the number of frames in this range.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.start.start_address()
+    }
+
+    /// Gets the size of this range, in bytes.
+    pub fn size(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+
+    /// Iterates over every frame in this range.
+    pub fn frames(&self) -> impl Iterator<Item=Frame> {
+        let start = self.start.number;
+        (start .. start + self.count).map(|number| Frame { number })
+    }
+}
+
+/// A frame allocator that is able to hand out physically contiguous runs of frames.
+pub trait ContiguousFrameAllocator: FrameAllocator {
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The address of the first frame is aligned to `align` bytes, which must be a power of two.
+    /// Alignments smaller than a page are treated as page alignment.
+    fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<FrameRange> {
+        self.alloc_contiguous_below(count, align, usize::MAX)
+    }
+
+    /// Allocates `count` physically contiguous frames that all lie below the given physical
+    /// address, for devices that can't reach all of memory.
+    fn alloc_contiguous_below(&mut self, count: usize, align: usize, limit: PhysicalAddress)
+        -> Option<FrameRange>;
+
+    /// Frees a run of frames that was handed out by `alloc_contiguous`.
+    fn dealloc_contiguous(&mut self, range: FrameRange);
+}
@@ -0,0 +1,72 @@
+use core::usize;
+use memory::{Frame, FrameAllocator, PhysicalAddress};
+
+/// A region of physical memory with its own addressing constraints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 16 MiB, which is reachable by legacy ISA DMA.
+    Dma,
+    /// Memory below 4 GiB, which is reachable by 32-bit devices.
+    Dma32,
+    /// All memory above 4 GiB.
+    Normal,
+}
+
+impl Zone {
+    /// Every zone, from the lowest addresses to the highest.
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// Gets the zone that the given physical address belongs to.
+    pub fn containing_address(address: PhysicalAddress) -> Self {
+        if address < Zone::Dma.end_address() {
+            Zone::Dma
+        } else if address < Zone::Dma32.end_address() {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// Gets the first physical address that belongs to this zone.
+    pub fn start_address(&self) -> PhysicalAddress {
+        match *self {
+            Zone::Dma => 0,
+            Zone::Dma32 => Zone::Dma.end_address(),
+            Zone::Normal => Zone::Dma32.end_address(),
+        }
+    }
+
+    /// Gets the address that this zone ends at (exclusive).
+    pub fn end_address(&self) -> PhysicalAddress {
+        match *self {
+            Zone::Dma => 16 * 1024 * 1024,
+            Zone::Dma32 => 4 * 1024 * 1024 * 1024,
+            Zone::Normal => usize::MAX,
+        }
+    }
+
+    /// Gets the index of this zone in `Zone::ALL`.
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+
+/// A frame allocator that is able to constrain allocations to a zone or an address limit.
+///
+/// Unconstrained allocations through `FrameAllocator::alloc` should prefer high memory, so that
+/// the scarce low zones are left for the devices that need them.
+pub trait ZonedFrameAllocator: FrameAllocator {
+    /// Allocates a frame from the given zone.
+    fn alloc_in(&mut self, zone: Zone) -> Option<Frame>;
+
+    /// Allocates a frame that lies entirely below the given physical address.
+    fn alloc_below(&mut self, limit: PhysicalAddress) -> Option<Frame>;
+}
@@ -0,0 +1,98 @@
+use spin::Mutex;
+use memory::{PAGE_SIZE, VirtualAddress};
+
+/// The largest number of guard pages that can be registered.
+const MAX_GUARD_PAGES: usize = 64;
+
+/// Every registered guard page.
+///
+/// This is a fixed-size table so that guard pages can be registered before the heap is set up.
+static GUARD_PAGES: Mutex<[Option<GuardPage>; MAX_GUARD_PAGES]> = Mutex::new([None; MAX_GUARD_PAGES]);
+
+/// An unmapped page directly below a stack, which faults when the stack overflows.
+#[derive(Debug, Clone, Copy)]
+pub struct GuardPage {
+    start: VirtualAddress,
+    stack_bottom: VirtualAddress,
+    stack_top: VirtualAddress,
+    name: &'static str,
+}
+
+impl GuardPage {
+    /// Creates a guard page for a stack.
+    ///
+    /// # Arguments
+    /// `start` - the page-aligned address of the guard page.
+    /// `stack_bottom` - the lowest address of the stack that the page guards.
+    /// `stack_top` - the address that the stack starts growing down from.
+    /// `name` - what the stack is used for, for reporting overflows.
+    pub fn new(start: VirtualAddress, stack_bottom: VirtualAddress, stack_top: VirtualAddress,
+               name: &'static str) -> Self {
+        assert!(start % PAGE_SIZE == 0, "Guard page address {:#x} is not page-aligned", start);
+        GuardPage { start, stack_bottom, stack_top, name }
+    }
+
+    /// Gets the address of the guard page.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Gets the lowest address of the stack that this page guards.
+    pub fn stack_bottom(&self) -> VirtualAddress {
+        self.stack_bottom
+    }
+
+    /// Gets the address that the stack that this page guards starts growing down from.
+    pub fn stack_top(&self) -> VirtualAddress {
+        self.stack_top
+    }
+
+    /// Gets what the stack that this page guards is used for.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets whether the given address lies in this guard page.
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.start + PAGE_SIZE
+    }
+}
+
+/// Registers a guard page, so that faults on it are reported as stack overflows.
+pub fn register_guard_page(guard: GuardPage) {
+    let mut guard_pages = GUARD_PAGES.lock();
+    let slot = guard_pages.iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many guard pages are registered");
+    *slot = Some(guard);
+}
+
+/// Unregisters every guard page that lies in the given range, once the memory that it was in has
+/// been handed back.
+///
+/// # Arguments
+/// `start` - the first address of the range.
+/// `end` - the end of the range (exclusive).
+pub fn unregister_guard_pages(start: VirtualAddress, end: VirtualAddress) {
+    let mut guard_pages = GUARD_PAGES.lock();
+    for slot in guard_pages.iter_mut() {
+        let in_range = slot.map(|guard| guard.start >= start && guard.start < end)
+            .unwrap_or(false);
+        if in_range {
+            *slot = None;
+        }
+    }
+}
+
+/// Finds the guard page that contains the given address.
+///
+/// This is meant to be called from the page fault handler, so it never waits for the registry
+/// lock; if it is held, `None` is returned.
+pub fn find_guard_page(address: VirtualAddress) -> Option<GuardPage> {
+    GUARD_PAGES.try_lock()
+        .and_then(|guard_pages| {
+            guard_pages.iter()
+                .filter_map(|slot| *slot)
+                .find(|guard| guard.contains(address))
+        })
+}
@@ -18,11 +18,6 @@ struct BuddyBlock {
 }
 
 impl BuddyBlock {
-    /// Gets whether this block is the bottom half of its buddy.
-    unsafe fn is_bottom(&self) -> bool {
-        self.address() < self.buddy().address()
-    }
-
     /// Gets this block from an address.
     #[inline]
     unsafe fn from_address(addr: usize) -> &'static mut Self {
@@ -61,17 +56,6 @@ impl BuddyBlock {
         buddy
     }
 
-    /// Gets the "cousin" to this block - that is, the block adjacent to this one, one order of
-    /// magnitude up.
-    unsafe fn next_adjacent(&mut self) -> &'static mut Self {
-        let offset = if self.is_bottom() {
-            2 << self.order
-        } else {
-            1 << self.order
-        };
-        BuddyBlock::from_address(self.address() + offset)
-    }
-    
     #[inline]
     fn address(&self) -> usize {
         self as *const _ as usize
@@ -94,9 +78,9 @@ pub struct BuddyAllocator {
     /// End of the heap in memory.
     heap_end: usize,
 
-    /// Max block order.
+    /// Max block size.
     ///
-    /// This is the largest order of a memory block.
+    /// This is the size of the largest block that the heap could be split into.
     max_block_size: usize,
 
     /// Min block size.
@@ -131,14 +115,8 @@ impl BuddyAllocator {
     pub unsafe fn init(&mut self) {
         assert!(!self.ready, "Attempted to initialize heap twice");
         let heap_size = self.heap_end - self.heap_start + 1;
-        if heap_size.is_power_of_two() {
-            self.max_block_size = heap_size;
-        } else {
-            panic!("Heap size must be a power of 2 for the time being");
-            self.max_block_size = 1 << log2(heap_size);
-        }
-
-        self.max_block_order = log2(self.max_block_size);
+        assert!(self.heap_start % self.min_block_size == 0 && heap_size % self.min_block_size == 0,
+                "Heap start and size must be multiples of {} bytes", self.min_block_size);
         self.min_block_order = log2(self.min_block_size);
         vgaprintln!("Heap size is {:#x} bytes spanning {:#x} to {:#x}", heap_size, self.heap_start, self.heap_end);
         //vgaprintln!("Min block size is {} bytes (order {})", self.min_block_size, self.min_block_order);
@@ -152,75 +130,50 @@ impl BuddyAllocator {
             addr += mem::size_of::<usize>();
         }
 
-        // set up the first block and its buddy
-        let block = BuddyBlock::from_address(self.heap_start);
-        block.order = self.max_block_order as u8;
-        // TODO : set up block buddies if the heap size is not a power of 2
+        // cover the heap with the largest blocks that fit. Each block has to be aligned to its own
+        // size so that its buddy can be found by address, and buddies that would stick out of the
+        // heap are never merged with
+        let mut addr = self.heap_start;
+        while addr < self.heap_end {
+            let remaining = self.heap_end - addr + 1;
+            let order = log2(remaining).min(addr.trailing_zeros() as usize);
+            let block = BuddyBlock::from_address(addr);
+            block.order = order as u8;
+            block.used = false;
+            self.max_block_order = self.max_block_order.max(order);
+            addr += 1 << order;
+        }
+        self.max_block_size = 1 << self.max_block_order;
         self.ready = true;
     }
 
-    /// Finds the next block of the given order, if any are available.
-    ///
-    /// This will split blocks as necessary.
-    ///
-    /// # Arguments
-    unsafe fn next_block(&self, order: usize, block_address: usize) -> Option<&BuddyBlock> {
-        let mut block = BuddyBlock::from_address(block_address);
-        let order = order as u8;
-
-        loop {
-            // break if the block's address has gone past the heap, our search is over
-            if block.address() >= self.heap_end {
-                break None;
-            }
+    /// Gets whether a block of the given order at the given address lies entirely inside of the
+    /// heap.
+    fn contains_block(&self, address: usize, order: usize) -> bool {
+        address >= self.heap_start && address + (1 << order) - 1 <= self.heap_end
+    }
 
+    /// Finds the first free block of the given order, if any are available.
+    ///
+    /// Blocks cover the whole heap, so they are walked one after another. The first free block
+    /// that is large enough is split down to the given order.
+    unsafe fn next_block(&self, order: usize) -> Option<&BuddyBlock> {
+        let mut addr = self.heap_start;
+        while addr < self.heap_end {
+            let block = BuddyBlock::from_address(addr);
             assert!((block.order as usize) <= self.max_block_order && (block.order as usize) >= self.min_block_order,
                     "Invalid block order at {:#x}: {}", block.address(), block.order);
 
-            if block.used {
-                // block is used
-                if block.order == order {
-                    if block.is_bottom() {
-                        let buddy = block.buddy();
-                        if buddy.used {
-                            block = block.next_adjacent();
-                        } else {
-                            buddy.used = true;
-                            break Some(buddy);
-                        }
-                    } else {
-                        block = block.next_adjacent();
-                    }
-                } else if block.order < order {
-                    block = block.next_adjacent();
-                } else if block.order > order {
-                    if block.is_bottom() {
-                        let buddy = block.buddy();
-                        if buddy.used {
-                            block = block.next_adjacent();
-                        } else {
-                            block = buddy;
-                        }
-                    } else {
-                        block = block.next_adjacent();
-                    }
-                } else {
-                    unreachable!();
-                }
-            } else {
-                // block is free
-                if block.order == order {
-                    block.used = true;
-                    break Some(block);
-                } else if block.order < order {
-                    block = block.next_adjacent();
-                } else if block.order > order {
+            if !block.used && (block.order as usize) >= order {
+                while (block.order as usize) > order {
                     block.split();
-                } else {
-                    unreachable!();
                 }
+                block.used = true;
+                return Some(block);
             }
+            addr += 1 << block.order;
         }
+        None
     }
 }
 
@@ -241,7 +194,7 @@ unsafe impl GlobalAlloc for BuddyAllocator {
         }
 
         // find the next block of the desired order
-        if let Some(block) = self.next_block(order, self.heap_start) {
+        if let Some(block) = self.next_block(order) {
             let block_addr = block as *const _ as usize;
             assert!(block_addr < self.heap_end);
             // offset by the bookkeeping size
@@ -254,16 +207,20 @@ unsafe impl GlobalAlloc for BuddyAllocator {
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
         let mut block = &mut *((ptr as usize - mem::size_of::<BuddyBlock>()) as *mut BuddyBlock);
         block.used = false;
-        let mut buddy = block.buddy();
-        // merge if this block's buddy is not being used either
-        while !buddy.used && (block.order as usize) < self.max_block_order && buddy.order == block.order {
+        // merge if this block's buddy is not being used either. Buddies that stick out of the heap
+        // only exist when the heap size isn't a power of 2, and they can't be merged with
+        while (block.order as usize) < self.max_block_order
+            && self.contains_block(block.address() ^ (1 << block.order), block.order as usize)
+        {
+            let buddy = block.buddy();
+            if buddy.used || buddy.order != block.order {
+                break;
+            }
             // find the first one in memory and increment its order, and unset the buddy's order
             block = block.first_half();
-            buddy = block.buddy();
+            let buddy = block.buddy();
             block.order += 1;
-            if buddy.address() <= self.heap_end {
-                buddy.order = 0;
-            }
+            buddy.order = 0;
         }
     }
 }
@@ -283,7 +240,7 @@ pub extern fn kernel_oom(_layout: Layout) -> ! {
 pub const KERNEL_HEAP_START: usize = memory::map::KERNEL_HEAP_START;
 
 /// The size of the kernel heap.
-pub const KERNEL_HEAP_SIZE: usize = 1024 * 64; // 64 kib
+pub const KERNEL_HEAP_SIZE: usize = memory::map::KERNEL_HEAP_SIZE;
 
 /// The heap allocator that is used for the kernel.
 pub const KERNEL_HEAP_ALLOCATOR: BuddyAllocator = BuddyAllocator::new(
@@ -2,11 +2,33 @@
 //!
 //! Every place in memory must be mapped in such a way that it does not overlap. This module helps
 //! lay out all memory regions in a single location so that conflicts are easy to spot.
+//!
+//! Every region of the kernel's half of the address space is also listed in `KERNEL_REGIONS`,
+//! which is checked for overlaps at compile time.
+
+use memory::PAGE_SIZE;
 
 /// Base for user-space virtual addresses.
 pub const USER_BASE: usize                              = 0x0000_0000_0010_0000
         ;
 
+/// The end of user-space virtual addresses (exclusive), where the lower half of the address space
+/// ends.
+pub const USER_END: usize                               = 0x0000_8000_0000_0000
+        ;
+
+/// Where user-space virtual addresses pick up again when five-level paging is enabled, at P5
+/// entry 1.
+///
+/// P5 entry 0 shares its P4 table with the kernel half, so the addresses from `USER_END` up to
+/// here are the kernel's.
+pub const USER_START_LA57: usize                        = 0x0001_0000_0000_0000
+        ;
+
+/// The end of user-space virtual addresses (exclusive) when five-level paging is enabled.
+pub const USER_END_LA57: usize                          = 0x0100_0000_0000_0000
+        ;
+
 /// The start address for the kernel.
 ///
 /// This starts 3/4s the way up in virtual memory.
@@ -18,7 +40,180 @@ pub const KERNEL_BASE: usize                            = 0xffff_8000_0000_0000
 
 /// This is an exported symbol that `ld` is able to use.
 
+/// The largest size of the kernel image, which is linked right above `KERNEL_BASE`.
+pub const KERNEL_IMAGE_SIZE: usize                      = 0x0000_0000_4000_0000
+        ;
+
+/// The physical address that the kernel is linked at, and which its image is mapped above
+/// `KERNEL_BASE` from.
+///
+/// NOTE : This should match what's in arch/x86_64/boot/link.ld
+const KERNEL_LINK_ADDRESS: usize                        = 0x0000_0000_0010_0000
+        ;
+
+/// The page that the VGA text buffer is mapped at.
+///
+/// This is in the kernel image's region, below `KERNEL_LINK_ADDRESS`, so that every address space
+/// can write to the screen. The boot page tables map the first GiB at `KERNEL_BASE` as well, so this works before the kernel is remapped too.
+pub const KERNEL_VGA_BUFFER: usize                      = 0x0000_0000_000b_8000
+        + KERNEL_BASE;
+
 /// The start address for the kernel's heap.
 pub const KERNEL_HEAP_START: usize                      = 0x0000_0000_4000_0000
         + KERNEL_BASE;
 
+/// The size of the kernel's heap.
+pub const KERNEL_HEAP_SIZE: usize                       = 0x0000_0000_0001_0000
+        ; // 64 KiB
+
+
+/// The start address for the physical frame allocator's bitmap.
+///
+/// One bit is used per frame, so this region has to be large enough for 1 bit per 4K of RAM.
+pub const KERNEL_FRAME_BITMAP_START: usize              = 0x0000_0000_8000_0000
+        + KERNEL_BASE;
+
+/// The largest size of the frame allocator's bitmap, which is enough to cover the physical map.
+pub const KERNEL_FRAME_BITMAP_SIZE: usize               = PHYSICAL_MAP_SIZE / PAGE_SIZE / 8
+        ;
+
+/// The start address for the stacks that are handed out by the stack allocator.
+pub const KERNEL_STACKS_START: usize                    = 0x0000_0001_0000_0000
+        + KERNEL_BASE;
+
+/// The size of the stack allocator's region.
+pub const KERNEL_STACKS_SIZE: usize                     = 100 * PAGE_SIZE
+        ;
+
+/// The start address for the per-frame metadata table.
+///
+/// This holds one `FrameInfo` for every physical frame.
+pub const KERNEL_FRAME_INFO_START: usize                = 0x0000_0080_0000_0000
+        + KERNEL_BASE;
+
+/// The largest size of the per-frame metadata table.
+pub const KERNEL_FRAME_INFO_SIZE: usize                 = 0x0000_0080_0000_0000
+        ;
+
+/// The start address for boot modules, which are mapped one after another.
+pub const KERNEL_MODULES_START: usize                   = 0x0000_0100_0000_0000
+        + KERNEL_BASE;
+
+/// The size of the region that boot modules are mapped into.
+pub const KERNEL_MODULES_SIZE: usize                    = 0x0000_0080_0000_0000
+        ;
+
+/// The start address for the direct map of all physical memory.
+///
+/// Physical address `x` can be accessed at `PHYSICAL_MAP_START + x`.
+pub const PHYSICAL_MAP_START: usize                     = 0x0000_4000_0000_0000
+        + KERNEL_BASE;
+
+/// The largest amount of physical memory that can be directly mapped.
+pub const PHYSICAL_MAP_SIZE: usize                      = 0x0000_2000_0000_0000
+        ;
+
+/// The page that is used to temporarily map inactive page tables while the kernel is remapped.
+pub const KERNEL_TEMPORARY_PAGE: usize                  = 0x0000_0000_7fff_f000
+        + KERNEL_BASE;
+
+/// The start address of the recursive mapping of the active page tables, through P4 entry 511.
+pub const KERNEL_RECURSIVE_START: usize                 = 0x0000_7f80_0000_0000
+        + KERNEL_BASE;
+
+/// The size of the recursive mapping.
+pub const KERNEL_RECURSIVE_SIZE: usize                  = 0x0000_0080_0000_0000
+        ;
+
+/// The start address for the kernel's virtual memory allocator.
+pub const KERNEL_VMALLOC_START: usize                   = 0x0000_0180_0000_0000
+        + KERNEL_BASE;
+
+/// The size of the region that the kernel's virtual memory allocator hands out memory from.
+pub const KERNEL_VMALLOC_SIZE: usize                    = 0x0000_0080_0000_0000
+        ;
+
+/// A region of the kernel's half of the address space.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Region {
+    const fn new(name: &'static str, start: usize, size: usize) -> Self {
+        Region { name, start, size }
+    }
+
+    /// Gets the last address of this region.
+    ///
+    /// This is inclusive, so that the region that ends at the top of the address space doesn't
+    /// overflow.
+    pub const fn last(&self) -> usize {
+        self.start + (self.size - 1)
+    }
+}
+
+/// Counts the tokens that it is given.
+macro_rules! count {
+    () => { 0 };
+    ($head:tt $($tail:tt)*) => { 1 + count!($($tail)*) };
+}
+
+/// Checks that each of the given regions, written as `start, size;`, ends before the next one
+/// starts.
+macro_rules! assert_regions_in_order {
+    ($start:expr, $size:expr;) => {};
+    ($start:expr, $size:expr; $next_start:expr, $next_size:expr; $($rest:tt)*) => {
+        const_assert!($start + ($size - 1) < $next_start);
+        assert_regions_in_order!($next_start, $next_size; $($rest)*);
+    };
+}
+
+/// Declares `KERNEL_REGIONS` from a list of regions, along with the checks that they are in order,
+/// so that a region can't be added without being checked.
+macro_rules! kernel_regions {
+    ($($name:expr, $start:expr, $size:expr;)*) => {
+        /// Every region of the kernel's half of the address space, from lowest to highest.
+        pub const KERNEL_REGIONS: [Region; count!($($name)*)] = [
+            $(Region::new($name, $start, $size),)*
+        ];
+
+        /// Checks that every region in `KERNEL_REGIONS` ends before the next one starts, which
+        /// means that the table is sorted and that no two regions overlap.
+        #[allow(dead_code)]
+        fn assert_kernel_regions_do_not_overlap() {
+            // user space comes before every region
+            assert_regions_in_order!(0, USER_END_LA57; $($start, $size;)*);
+        }
+    };
+}
+
+kernel_regions! {
+    "kernel image", KERNEL_BASE, KERNEL_IMAGE_SIZE;
+    "heap", KERNEL_HEAP_START, KERNEL_HEAP_SIZE;
+    "temporary page", KERNEL_TEMPORARY_PAGE, PAGE_SIZE;
+    "frame bitmap", KERNEL_FRAME_BITMAP_START, KERNEL_FRAME_BITMAP_SIZE;
+    "stacks", KERNEL_STACKS_START, KERNEL_STACKS_SIZE;
+    "frame info", KERNEL_FRAME_INFO_START, KERNEL_FRAME_INFO_SIZE;
+    "modules", KERNEL_MODULES_START, KERNEL_MODULES_SIZE;
+    "vmalloc", KERNEL_VMALLOC_START, KERNEL_VMALLOC_SIZE;
+    "physical map", PHYSICAL_MAP_START, PHYSICAL_MAP_SIZE;
+    "recursive map", KERNEL_RECURSIVE_START, KERNEL_RECURSIVE_SIZE;
+}
+
+/// Checks that the VGA buffer lies in the kernel image's region, below where the kernel is linked.
+#[allow(dead_code)]
+fn assert_vga_buffer_below_kernel() {
+    const_assert!(KERNEL_VGA_BUFFER >= KERNEL_BASE);
+    const_assert!(KERNEL_VGA_BUFFER + PAGE_SIZE <= KERNEL_BASE + KERNEL_LINK_ADDRESS);
+}
+
+/// Prints every region of the kernel's half of the address space.
+pub fn print_kernel_regions() {
+    vgaprintln!("Kernel memory map:");
+    for region in KERNEL_REGIONS.iter() {
+        vgaprintln!("  {:#018x} - {:#018x} {}", region.start, region.last(), region.name);
+    }
+}
@@ -0,0 +1,81 @@
+use core::{mem, ptr};
+use memory::{PAGE_SIZE, PhysicalAddress, VirtualAddress, VirtualRegion};
+
+/// A range of memory-mapped device memory, such as APIC or HPET registers, a PCI BAR, or a
+/// framebuffer, that is mapped into kernel virtual memory by `MemoryController::map_mmio`.
+///
+/// Every access through `read` and `write` is volatile, so the compiler never merges, reorders
+/// or drops it.
+#[derive(Debug)]
+pub struct Mmio {
+    region: VirtualRegion,
+    physical_start: PhysicalAddress,
+    size: usize,
+}
+
+impl Mmio {
+    /// Wraps a virtual region that `physical_start` has been mapped into.
+    ///
+    /// `physical_start` doesn't have to be page-aligned; its offset into the first page is kept.
+    pub (in memory) fn new(region: VirtualRegion, physical_start: PhysicalAddress, size: usize) -> Self {
+        assert!(physical_start % PAGE_SIZE + size <= region.size(),
+                "MMIO range {:#x} - {:#x} doesn't fit in its virtual region", physical_start, physical_start + size);
+        Mmio { region, physical_start, size }
+    }
+
+    /// Gets the virtual address that the device memory starts at.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.region.start_address() + self.physical_start % PAGE_SIZE
+    }
+
+    /// Gets the physical address that the device memory starts at.
+    pub fn physical_start(&self) -> PhysicalAddress {
+        self.physical_start
+    }
+
+    /// Gets the size of the device memory, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads a register.
+    ///
+    /// # Arguments
+    /// `offset` - the offset of the register from the start of the device memory, which must be
+    ///            aligned to the size of `T`.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.register::<T>(offset)) }
+    }
+
+    /// Writes a register.
+    ///
+    /// # Arguments
+    /// `offset` - the offset of the register from the start of the device memory, which must be
+    ///            aligned to the size of `T`.
+    /// `value` - the value to write.
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.register::<T>(offset), value) }
+    }
+
+    /// Gets a raw pointer to the start of the device memory, for bulk access like filling a
+    /// framebuffer.
+    ///
+    /// The pointer is only valid for as long as this mapping is.
+    pub fn as_mut_ptr<T>(&mut self) -> *mut T {
+        self.start_address() as *mut T
+    }
+
+    /// Gives back the virtual region, once the device memory has been unmapped from it.
+    pub (in memory) fn into_region(self) -> VirtualRegion {
+        self.region
+    }
+
+    /// Gets a pointer to the register at `offset`, making sure that it lies in the device memory.
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + mem::size_of::<T>() <= self.size,
+                "MMIO register at offset {:#x} is out of bounds (size is {:#x})", offset, self.size);
+        let address = self.start_address() + offset;
+        assert!(address % mem::align_of::<T>() == 0, "MMIO register at {:#x} is misaligned", address);
+        address as *mut T
+    }
+}
@@ -1,44 +1,136 @@
 mod frame;
 mod paging;
 mod heap;
+mod modules;
+mod stats;
+mod vmalloc;
+mod demand;
+mod address_space;
+mod guard;
+mod mmio;
 pub mod map;
 
 pub use self::frame::*;
 pub use self::paging::*;
 pub use self::heap::*;
+pub use self::modules::*;
+pub use self::stats::*;
+pub use self::vmalloc::*;
+pub use self::demand::*;
+pub use self::address_space::*;
+pub use self::guard::*;
+pub use self::mmio::*;
 
-use multiboot2::{BootInformation, ElfSection};
+use boot_info::BootInfo;
+use spin::{Mutex, Once};
+use memory::map::{
+    KERNEL_BASE, KERNEL_IMAGE_SIZE, KERNEL_FRAME_BITMAP_START, KERNEL_FRAME_BITMAP_SIZE, KERNEL_FRAME_INFO_START,
+    KERNEL_FRAME_INFO_SIZE, KERNEL_STACKS_START, KERNEL_STACKS_SIZE, KERNEL_VMALLOC_START, KERNEL_VMALLOC_SIZE,
+    print_kernel_regions,
+};
 use arch::x86_64::stack::*;
 
+/// The kernel's memory controller, which is set up by `init`.
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController<BitmapFrameAllocator>>> = Once::new();
+
+/// Gets the kernel's memory controller.
+///
+/// Panics if memory has not been initialized yet.
+pub fn controller() -> &'static Mutex<MemoryController<BitmapFrameAllocator>> {
+    MEMORY_CONTROLLER.try()
+        .expect("Memory controller has not been initialized")
+}
+
+/// Handles a page fault, if it was caused by touching a lazily backed region for the first time,
+/// or by writing to a copy-on-write page.
+///
+/// Returns whether the fault was handled. Lazily backed pages are mapped even while the memory
+/// controller is locked, as long as the frames that were set aside for them last, but
+/// copy-on-write faults that happen while it is locked can't be handled.
+pub fn handle_page_fault(fault: &PageFault) -> bool {
+    let controller = match MEMORY_CONTROLLER.try() {
+        Some(controller) => controller,
+        None => return false,
+    };
+    if fault.present {
+        return controller.try_lock()
+            .map(|mut controller| controller.handle_copy_on_write(fault))
+            .unwrap_or(false);
+    }
+    // top up the frames for lazily backed pages while the controller can hand them out
+    if let Some(mut controller) = controller.try_lock() {
+        refill_lazy_frames(&mut controller.frame_allocator);
+    }
+    handle_lazy_fault(fault)
+}
+
 #[cfg(not(test))]
 /// Initializes main memory and remaps the kernel.
-pub fn init(boot_info: BootInformation) -> MemoryController<impl FrameAllocator> {
+///
+/// The memory controller that is set up is also available through `controller`.
+pub fn init(boot_info: &'static BootInfo) -> &'static Mutex<MemoryController<BitmapFrameAllocator>> {
     //assert_has_not_been_called!("memory::init must be called exactly once");
 
-    let memory_map = boot_info.memory_map_tag()
-        .expect("Could not find memory map tag in multiboot2 data");
-    let elf_sections = boot_info.elf_sections_tag()
-        .expect("Could not find ELF sections tag in multiboot2 data");
+    let elf_sections = boot_info.elf_sections();
+    print_memory_map(boot_info.memory_areas());
+    print_kernel_regions();
 
-    let kernel_start = elf_sections.sections()
-        .filter(|s| s.is_allocated() && !s.name().ends_with(".early"))
+    let kernel_start = elf_sections.iter()
+        .filter(|s| !s.name().ends_with(".early"))
         .map(|s| s.start_address())
         .min()
         .unwrap();
-    let kernel_end = elf_sections.sections()
-        .filter(|s| s.is_allocated() && !s.name().ends_with(".early"))
-        .map(|s| s.start_address() + s.size())
+    let kernel_end = elf_sections.iter()
+        .filter(|s| !s.name().ends_with(".early"))
+        .map(|s| s.end_address())
         .max()
         .unwrap();
     vgaprintln!("Kernel start: {:#x}", kernel_start);
     vgaprintln!("Kernel end  : {:#x}", kernel_end);
+    assert!(kernel_end <= KERNEL_BASE + KERNEL_IMAGE_SIZE,
+            "Kernel image ends at {:#x}, past the end of its region", kernel_end);
 
-    let mut frame_allocator = AreaFrameAllocator::new(memory_map.memory_areas(),
-        kernel_start as usize, kernel_end as usize,
-        boot_info.start_address(), boot_info.end_address());
+    // the identity-mapped .early sections, which are only needed until the kernel is done booting
+    let early_start = elf_sections.iter()
+        .filter(|s| s.name().ends_with(".early"))
+        .map(|s| s.start_address())
+        .min()
+        .unwrap();
+    let early_end = elf_sections.iter()
+        .filter(|s| s.name().ends_with(".early"))
+        .map(|s| (s.end_address() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+        .max()
+        .unwrap();
+
+    // the physical range of the kernel, including the identity-mapped .early sections
+    let kernel_phys_start = elf_sections.iter()
+        .map(|s| physical_address(s.start_address()))
+        .min()
+        .unwrap();
+    let kernel_phys_end = elf_sections.iter()
+        .map(|s| physical_address(s.end_address()))
+        .max()
+        .unwrap();
+
+    // the multiboot2 information has been copied out already, so its frames are free to use
+    let mut boot_allocator = AreaFrameAllocator::new(boot_info.memory_areas(),
+        kernel_phys_start, kernel_phys_end);
+    for module in boot_info.modules() {
+        boot_allocator.reserve(module.start_address(), module.end_address());
+    }
 
     // map the kernel and get the active page table
-    let mut active_table = remap_kernel(&mut frame_allocator, &boot_info);
+    let mut active_table = remap_kernel(&mut boot_allocator, boot_info);
+    #[cfg(feature = "physical-map")]
+    init_physical_map(boot_info, &mut active_table, &mut boot_allocator);
+
+    // hand off the rest of memory to the bitmap allocator
+    let mut frame_allocator = init_frame_allocator(&mut active_table, &mut boot_allocator, boot_info,
+        (kernel_phys_start, kernel_phys_end));
+    init_frame_info(&mut active_table, &mut frame_allocator);
+    init_kernel_tables(&mut active_table, &mut frame_allocator);
+
+    let boot_stats = MemoryStats::from_memory_map(boot_info.memory_areas(), (kernel_phys_start, kernel_phys_end));
 
     // map the heap
     let heap_start = Page::containing_address(KERNEL_HEAP_START);
@@ -46,7 +138,7 @@ pub fn init(boot_info: BootInformation) -> MemoryController<impl FrameAllocator>
 
     vgaprintln!("Mapping heap from {:#x} to {:#x}", heap_start.start_address(), heap_end.start_address() + 4095);
     for page in Page::range_inclusive(heap_start, heap_end) {
-        active_table.map(page, EntryFlags::WRITABLE, &mut frame_allocator);
+        active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NOEXEC, &mut frame_allocator);
     }
 
     // final heap initializations
@@ -56,16 +148,120 @@ pub fn init(boot_info: BootInformation) -> MemoryController<impl FrameAllocator>
         alloc.init();
     }
 
+    // boot modules are recorded on the heap
+    init_boot_modules(boot_info, &mut active_table, &mut frame_allocator);
+
+    let vmalloc = VirtualAllocator::new(KERNEL_VMALLOC_START, KERNEL_VMALLOC_START + KERNEL_VMALLOC_SIZE);
+
     // TODO(arch) pretty sure this is x86-specific
-    let stack_alloc_start = heap_end + 1;
-    // reserve 100 pages for interrupt stack allocation
-    let stack_alloc_end = stack_alloc_start + 100;
-    let stack_allocator = StackAllocator::new(Page::range_inclusive(stack_alloc_start, stack_alloc_end));
+    let stacks_start = Page::containing_address(KERNEL_STACKS_START);
+    let stacks_end = Page::containing_address(KERNEL_STACKS_START + KERNEL_STACKS_SIZE - 1);
+    let stack_allocator = StackAllocator::new(Page::range_inclusive(stacks_start, stacks_end));
+
+    // everything that the kernel needs is mapped by now
+    audit_mappings(&active_table, boot_info);
 
-    MemoryController {
+    let memory_controller = MemoryController {
         active_table,
         frame_allocator,
         stack_allocator,
+        vmalloc,
+        boot_stats,
+        early_memory: Some((early_start, early_end)),
+    };
+    memory_controller.stats().print();
+    MEMORY_CONTROLLER.call_once(|| Mutex::new(memory_controller))
+}
+
+/// Sets up the bitmap frame allocator, which takes over from the boot frame allocator.
+///
+/// Every usable frame from the memory map is tracked, except for frames that were already handed
+/// out by `boot_allocator` and frames that belong to the kernel or boot modules.
+#[cfg(not(test))]
+fn init_frame_allocator(active_table: &mut ActivePageTable, boot_allocator: &mut AreaFrameAllocator,
+                        boot_info: &BootInfo, kernel: (usize, usize)) -> BitmapFrameAllocator
+{
+    let frame_count = boot_info.usable_memory_areas()
+        .map(|area| area.end() / PAGE_SIZE)
+        .max()
+        .expect("Could not find any usable memory areas in the memory map");
+
+    // map the bitmap using frames from the boot allocator
+    let bitmap_size = BitmapFrameAllocator::bitmap_size(frame_count);
+    assert!(bitmap_size <= KERNEL_FRAME_BITMAP_SIZE,
+            "Frame bitmap of {} bytes does not fit in its region", bitmap_size);
+    let bitmap_start = Page::containing_address(KERNEL_FRAME_BITMAP_START);
+    let bitmap_end = Page::containing_address(KERNEL_FRAME_BITMAP_START + bitmap_size - 1);
+    for page in Page::range_inclusive(bitmap_start, bitmap_end) {
+        active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NOEXEC, boot_allocator);
+    }
+
+    let mut allocator = unsafe { BitmapFrameAllocator::new(KERNEL_FRAME_BITMAP_START, frame_count) };
+    for area in boot_info.usable_memory_areas() {
+        // only frames that are entirely inside of the area are usable
+        let start_frame = Frame::containing_address(area.start + PAGE_SIZE - 1);
+        let end_frame = Frame::containing_address(area.end());
+        if start_frame < end_frame {
+            allocator.free_range(start_frame, Frame { number: end_frame.number - 1 });
+        }
+    }
+
+    // everything below the boot allocator's next frame has been handed out already
+    let boot_next_frame = boot_allocator.next_frame();
+    if boot_next_frame.number > 0 {
+        allocator.reserve_range(Frame { number: 0 }, Frame { number: boot_next_frame.number - 1 });
+    }
+    // except for frames that the boot allocator was given back
+    for frame in boot_allocator.freed_frames() {
+        allocator.free_range(frame.clone(), frame);
+    }
+    allocator.reserve_range(Frame::containing_address(kernel.0), Frame::containing_address(kernel.1 - 1));
+    for module in boot_info.modules() {
+        let (start, end) = (module.start_address(), module.end_address());
+        if end > start {
+            allocator.reserve_range(Frame::containing_address(start), Frame::containing_address(end - 1));
+        }
+    }
+
+    vgaprintln!("Frame allocator tracking {} frames ({} free), bitmap is {} bytes",
+                allocator.frame_count(), allocator.free_count(), bitmap_size);
+    for zone in Zone::ALL.iter() {
+        vgaprintln!("  Zone {:<6}: {} frames free", zone.name(), allocator.zone_free_count(*zone));
+    }
+    allocator
+}
+
+/// Maps and sets up the per-frame metadata table, covering every frame that the frame allocator
+/// tracks.
+#[cfg(not(test))]
+fn init_frame_info(active_table: &mut ActivePageTable, frame_allocator: &mut BitmapFrameAllocator) {
+    let frame_count = frame_allocator.frame_count();
+    let table_size = FrameInfo::table_size(frame_count);
+    assert!(table_size <= KERNEL_FRAME_INFO_SIZE,
+            "Frame metadata table of {} bytes does not fit in its region", table_size);
+    let table_start = Page::containing_address(KERNEL_FRAME_INFO_START);
+    let table_end = Page::containing_address(KERNEL_FRAME_INFO_START + table_size - 1);
+    for page in Page::range_inclusive(table_start, table_end) {
+        active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NOEXEC, frame_allocator);
+    }
+    unsafe { FrameInfo::init_table(KERNEL_FRAME_INFO_START, frame_count); }
+
+    // anything that is in use at this point was never handed out by the frame allocator
+    for number in 0 .. frame_count {
+        let frame = Frame { number };
+        if !frame_allocator.is_free(&frame) {
+            frame.info().insert_flags(FrameFlags::RESERVED);
+        }
+    }
+    vgaprintln!("Frame metadata table is {} bytes", table_size);
+}
+
+/// Converts the address of an allocated ELF section into the physical address it was loaded at.
+fn physical_address(address: usize) -> usize {
+    if address >= KERNEL_BASE {
+        address - KERNEL_BASE
+    } else {
+        address
     }
 }
 
@@ -73,15 +269,235 @@ pub struct MemoryController<F: FrameAllocator> {
     active_table: ActivePageTable,
     frame_allocator: F,
     stack_allocator: StackAllocator<PageRangeIter>,
+    vmalloc: VirtualAllocator,
+    /// Memory statistics that were gathered at boot, without the free and allocated counts.
+    boot_stats: MemoryStats,
+    /// The identity-mapped `.early` sections, which hold the multiboot2 header, the boot code, the
+    /// boot stack and the boot page tables, until they are reclaimed.
+    early_memory: Option<(VirtualAddress, VirtualAddress)>,
 }
 
 impl<F: FrameAllocator> MemoryController<F> {
     pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        free_deferred_tables(&mut self.frame_allocator);
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
             ref mut stack_allocator,
+            ..
         } = self;
         stack_allocator.alloc(active_table, frame_allocator, size_in_pages)
     }
+
+    /// Reserves `count` pages of kernel virtual memory, without mapping them.
+    pub fn alloc_virtual(&mut self, count: usize) -> Option<VirtualRegion> {
+        self.vmalloc.alloc(count)
+    }
+
+    /// Reserves `count` pages of kernel virtual memory and backs them with newly allocated frames.
+    pub fn alloc_virtual_backed(&mut self, count: usize, flags: EntryFlags) -> Option<VirtualRegion> {
+        free_deferred_tables(&mut self.frame_allocator);
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut vmalloc,
+            ..
+        } = self;
+        vmalloc.alloc_backed(count, flags, active_table, frame_allocator)
+    }
+
+    /// Reserves `count` pages of kernel virtual memory, which are backed by zeroed frames the first
+    /// time they are touched.
+    pub fn alloc_virtual_lazy(&mut self, count: usize, flags: EntryFlags) -> Option<VirtualRegion> {
+        let region = self.vmalloc.alloc(count)?;
+        register_lazy_region(region.start_address(), region.end_address(), flags);
+        refill_lazy_frames(&mut self.frame_allocator);
+        Some(region)
+    }
+
+    /// Gives back a region of kernel virtual memory, releasing its frames if it is backed.
+    pub fn dealloc_virtual(&mut self, region: VirtualRegion) {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut vmalloc,
+            ..
+        } = self;
+        // lazy regions only have the pages that were touched mapped
+        if unregister_lazy_region(region.start_address()).is_some() {
+            for page in region.pages() {
+                if active_table.translate_page(page).is_some() {
+                    active_table.unmap_and_free(page, frame_allocator);
+                }
+            }
+        }
+        vmalloc.dealloc(region, active_table, frame_allocator)
+    }
+
+    /// Maps a range of device memory into kernel virtual memory.
+    ///
+    /// The range is mapped writable and non-executable, with the given memory type. Its frames
+    /// belong to the device, so they never come from or go back to the frame allocator.
+    ///
+    /// # Arguments
+    /// `physical_start` - the physical address that the device memory starts at.
+    /// `size` - the size of the device memory, in bytes.
+    /// `memory_type` - how accesses to the device memory are cached.
+    pub fn map_mmio(&mut self, physical_start: PhysicalAddress, size: usize, memory_type: MemoryType)
+        -> Result<Mmio, MapError>
+    {
+        assert!(size > 0, "Attempted to map an empty MMIO range at {:#x}", physical_start);
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut vmalloc,
+            ..
+        } = self;
+        let start_frame = Frame::containing_address(physical_start);
+        let end_frame = Frame::containing_address(physical_start + size - 1);
+        let count = end_frame.number - start_frame.number + 1;
+        let region = vmalloc.alloc(count)
+            .ok_or(MapError::OutOfVirtualMemory)?;
+
+        let flags = EntryFlags::WRITABLE | EntryFlags::NOEXEC | memory_type.flags();
+        let frames = Frame::range_inclusive(start_frame, end_frame);
+        for (mapped, (page, frame)) in region.pages().zip(frames).enumerate() {
+            if let Err(error) = active_table.try_map_to(page, frame, flags, frame_allocator) {
+                for page in region.pages().take(mapped) {
+                    active_table.unmap(page, frame_allocator);
+                }
+                vmalloc.dealloc(region, active_table, frame_allocator);
+                return Err(error);
+            }
+        }
+        Ok(Mmio::new(region, physical_start, size))
+    }
+
+    /// Unmaps a range of device memory that was mapped by `map_mmio`.
+    pub fn unmap_mmio(&mut self, mmio: Mmio) {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut vmalloc,
+            ..
+        } = self;
+        let region = mmio.into_region();
+        for page in region.pages() {
+            active_table.unmap(page, frame_allocator);
+        }
+        vmalloc.dealloc(region, active_table, frame_allocator);
+    }
+
+    /// Registers a range of virtual memory whose pages are backed by zeroed frames the first time
+    /// they are touched.
+    ///
+    /// The range must not be mapped already, and it must not be handed out by anything else.
+    pub fn register_lazy(&mut self, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags) {
+        register_lazy_region(start, end, flags);
+        refill_lazy_frames(&mut self.frame_allocator);
+    }
+
+    /// Handles a page fault by copying the page, if it was a write to a copy-on-write page.
+    ///
+    /// Returns whether the fault was handled.
+    pub fn handle_copy_on_write(&mut self, fault: &PageFault) -> bool {
+        if !fault.present || !fault.write {
+            return false;
+        }
+        let page = Page::containing_address(fault.address);
+        self.active_table.copy_on_write(page, &mut self.frame_allocator)
+            .unwrap_or(false)
+    }
+
+    /// Clones the active address space, sharing its user-half pages copy-on-write.
+    ///
+    /// The page tables of address spaces that were dropped while the controller was locked are
+    /// freed first.
+    pub fn clone_address_space(&mut self) -> Result<InactivePageTable, MapError> {
+        free_deferred_tables(&mut self.frame_allocator);
+        self.active_table.clone_copy_on_write(&mut self.frame_allocator)
+    }
+
+    /// Unmaps the `.early` sections and gives their frames back to the frame allocator.
+    ///
+    /// This must be called once the kernel no longer runs on the boot stack, and after the boot
+    /// GDT has been replaced. Calling this more than once does nothing.
+    pub fn reclaim_boot_memory(&mut self) {
+        let (early_start, early_end) = match self.early_memory.take() {
+            Some(early_memory) => early_memory,
+            None => return,
+        };
+        let stack_address = &early_start as *const _ as usize;
+        assert!(stack_address < early_start || stack_address >= early_end,
+                "Attempted to reclaim boot memory while running on the boot stack");
+
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ..
+        } = self;
+
+        // the .early sections are identity mapped, so every page is its own frame
+        vgaprintln!("Reclaiming early sections from {:#x} - {:#x}", early_start, early_end);
+        unregister_guard_pages(early_start, early_end);
+        let early_pages = Page::range_inclusive(Page::containing_address(early_start),
+                                                Page::containing_address(early_end - 1));
+        for page in early_pages {
+            match active_table.try_unmap(page, frame_allocator) {
+                // the old boot P2 table was already unmapped to be the boot stack's guard page
+                Ok(_) | Err(MapError::NotMapped) => {},
+                Err(error) => panic!("Could not unmap early page {:#x}: {}", page.start_address(), error),
+            }
+            frame_allocator.dealloc(Frame::containing_address(page.start_address()));
+        }
+    }
+
+    /// Returns a frame to the frame allocator.
+    pub fn dealloc_frame(&mut self, frame: Frame) {
+        self.frame_allocator.dealloc(frame)
+    }
+
+    /// Releases a reference to a frame, returning it to the frame allocator if it was the last
+    /// one.
+    pub fn release_frame(&mut self, frame: Frame) -> usize {
+        frame.dec_ref(&mut self.frame_allocator)
+    }
+}
+
+impl MemoryController<BitmapFrameAllocator> {
+    /// Gets the current physical memory statistics.
+    pub fn stats(&self) -> MemoryStats {
+        self.boot_stats.with_free(self.frame_allocator.free_count())
+    }
+}
+
+impl<F: ContiguousFrameAllocator> MemoryController<F> {
+    /// Allocates `count` physically contiguous frames, aligned to `align` bytes.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<FrameRange> {
+        self.frame_allocator.alloc_contiguous(count, align)
+    }
+
+    /// Allocates `count` physically contiguous frames below `limit`, aligned to `align` bytes.
+    pub fn alloc_contiguous_below(&mut self, count: usize, align: usize, limit: PhysicalAddress)
+        -> Option<FrameRange>
+    {
+        self.frame_allocator.alloc_contiguous_below(count, align, limit)
+    }
+
+    /// Frees a run of frames that was handed out by `alloc_contiguous`.
+    pub fn dealloc_contiguous(&mut self, range: FrameRange) {
+        self.frame_allocator.dealloc_contiguous(range)
+    }
+}
+
+impl<F: ZonedFrameAllocator> MemoryController<F> {
+    /// Allocates a frame from the given zone.
+    pub fn alloc_frame_in(&mut self, zone: Zone) -> Option<Frame> {
+        self.frame_allocator.alloc_in(zone)
+    }
+
+    /// Allocates a frame that lies entirely below the given physical address.
+    pub fn alloc_frame_below(&mut self, limit: PhysicalAddress) -> Option<Frame> {
+        self.frame_allocator.alloc_below(limit)
+    }
 }
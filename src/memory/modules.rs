@@ -0,0 +1,94 @@
+use core::slice;
+use alloc::vec::Vec;
+use boot_info::BootInfo;
+use spin::Once;
+use memory::{
+    PAGE_SIZE, Frame, FrameAllocator, Page, ActivePageTable, EntryFlags, PhysicalAddress,
+    VirtualAddress, map::{KERNEL_MODULES_START, KERNEL_MODULES_SIZE},
+};
+
+/// Every module that was loaded by the bootloader.
+static BOOT_MODULES: Once<Vec<BootModule>> = Once::new();
+
+/// A module that was loaded by the bootloader (e.g. with GRUB's `module2` command).
+///
+/// Module contents are mapped read-only into kernel memory.
+#[derive(Debug)]
+pub struct BootModule {
+    start: PhysicalAddress,
+    end: PhysicalAddress,
+    virtual_start: VirtualAddress,
+    cmdline: &'static str,
+}
+
+impl BootModule {
+    /// Gets the physical address that this module starts at.
+    pub fn start_address(&self) -> PhysicalAddress {
+        self.start
+    }
+
+    /// Gets the physical address that this module ends at (exclusive).
+    pub fn end_address(&self) -> PhysicalAddress {
+        self.end
+    }
+
+    /// Gets the size of this module, in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Gets the contents of this module.
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.virtual_start as *const u8, self.size()) }
+    }
+
+    /// Gets the command line that was passed along with this module.
+    pub fn cmdline(&self) -> &'static str {
+        self.cmdline
+    }
+}
+
+/// Gets every module that was loaded by the bootloader.
+pub fn boot_modules() -> &'static [BootModule] {
+    BOOT_MODULES.try()
+        .map(|modules| &modules[..])
+        .unwrap_or(&[])
+}
+
+/// Maps all boot modules into kernel memory and records them for `boot_modules`.
+///
+/// This must be called after the heap is set up, since the modules are recorded in it. The frames
+/// of each module must already be reserved in the frame allocator.
+pub (in memory) fn init_boot_modules<A>(boot_info: &'static BootInfo, active_table: &mut ActivePageTable,
+                                        allocator: &mut A)
+    where A: FrameAllocator
+{
+    let mut next_page = Page::containing_address(KERNEL_MODULES_START);
+    let modules = boot_info.modules().iter()
+        .map(|module| {
+            let start = module.start_address();
+            let end = module.end_address();
+            let virtual_start = next_page.start_address() + start % PAGE_SIZE;
+            if end > start {
+                let frames = Frame::range_inclusive(Frame::containing_address(start),
+                                                    Frame::containing_address(end - 1));
+                for frame in frames {
+                    assert!(next_page.start_address() < KERNEL_MODULES_START + KERNEL_MODULES_SIZE,
+                            "Boot modules do not fit in their region");
+                    active_table.map_to(next_page, frame, EntryFlags::NOEXEC, allocator);
+                    next_page = next_page + 1;
+                }
+                // leave an unmapped page between modules
+                next_page = next_page + 1;
+            }
+            vgaprintln!("Boot module at {:#x} - {:#x}: {}", start, end, module.cmdline());
+            BootModule {
+                start,
+                end,
+                virtual_start,
+                cmdline: module.cmdline(),
+            }
+        })
+        .collect();
+    BOOT_MODULES.call_once(|| modules);
+}
@@ -0,0 +1,84 @@
+//! A boot-time audit of the kernel's page tables, which makes sure that `remap_kernel` and
+//! everything after it mapped memory the way it was supposed to.
+
+use boot_info::BootInfo;
+use memory::{PAGE_SIZE, find_guard_page, map::KERNEL_BASE};
+use memory::paging::{Mapper, Mapping, EntryFlags, VirtualAddress};
+
+/// Walks every mapping of the kernel's page table, and panics if any of them is unsafe.
+///
+/// The audit fails if:
+/// * a page is both writable and executable;
+/// * anything in the lower half is mapped besides the identity-mapped `.early` sections, which
+///   means that something was left over from the boot page tables;
+/// * an allocated ELF section is unmapped, or is mapped with different permissions than the
+///   section asks for.
+///
+/// Every problem is printed before panicking, so they can all be fixed in one go.
+pub fn audit_mappings(mapper: &Mapper, boot_info: &BootInfo) {
+    let mut problems = 0;
+
+    for mapping in mapper.mappings() {
+        if mapping.flags.contains(EntryFlags::WRITABLE) && !mapping.flags.contains(EntryFlags::NOEXEC) {
+            vgaprintln!("Audit: writable and executable: {}", mapping);
+            problems += 1;
+        }
+        if mapping.start >= KERNEL_BASE {
+            continue;
+        }
+        let identity_mapped = |address: VirtualAddress| {
+            boot_info.elf_sections().iter()
+                .filter(|section| section.start_address() < KERNEL_BASE)
+                .any(|section| address >= section.start_address() && address < section.end_address())
+        };
+        let stale = (mapping.start .. mapping.end).step_by(PAGE_SIZE)
+            .any(|address| !identity_mapped(address));
+        if stale {
+            vgaprintln!("Audit: stale lower-half mapping: {}", mapping);
+            problems += 1;
+        }
+    }
+
+    for section in boot_info.elf_sections() {
+        let (start, end) = (section.start_address(), section.end_address());
+        let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let expected = EntryFlags::from(section.flags()) & (EntryFlags::WRITABLE | EntryFlags::NOEXEC);
+
+        // the mappings come in order, so any gap between them is unmapped
+        let mut mapped_to = skip_guard_pages(start);
+        let overlapping = mapper.mappings()
+            .skip_while(|mapping| mapping.end <= start)
+            .take_while(|mapping| mapping.start < end);
+        for mapping in overlapping {
+            if mapping.start > mapped_to {
+                break;
+            }
+            if !has_permissions(&mapping, expected) {
+                vgaprintln!("Audit: section {} should be {:?}: {}", section.name(), expected, mapping);
+                problems += 1;
+            }
+            mapped_to = skip_guard_pages(mapping.end);
+        }
+        if mapped_to < end {
+            vgaprintln!("Audit: section {} is not mapped at {:#x}", section.name(), mapped_to);
+            problems += 1;
+        }
+    }
+
+    assert!(problems == 0, "Page table audit found {} problem(s)", problems);
+    vgaprintln!("Page table audit passed");
+}
+
+/// Skips over the guard pages that start at `address`, which are meant to be unmapped, like the
+/// boot stack's guard page in `.bss.early`.
+fn skip_guard_pages(mut address: VirtualAddress) -> VirtualAddress {
+    while find_guard_page(address).is_some() {
+        address += PAGE_SIZE;
+    }
+    address
+}
+
+/// Gets whether a mapping is writable and executable exactly when `expected` says so.
+fn has_permissions(mapping: &Mapping, expected: EntryFlags) -> bool {
+    mapping.flags & (EntryFlags::WRITABLE | EntryFlags::NOEXEC) == expected
+}
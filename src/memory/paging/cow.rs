@@ -0,0 +1,294 @@
+//! Copy-on-write sharing of user memory between address spaces.
+//!
+//! When an address space is cloned, the user half of its page tables is copied, but the frames
+//! behind it are shared. Writable pages are made read-only and marked `COPY_ON_WRITE` in both
+//! address spaces; the first write to such a page faults, and the faulting address space gets its
+//! own copy of the frame.
+//!
+//! Cloning and copying go through the physical map, so it must be set up.
+
+use core::ptr;
+use memory::{PAGE_SIZE, map::KERNEL_BASE};
+use memory::frame::{Frame, FrameAllocator, FrameInfo, FrameFlags};
+use memory::paging::{
+    EditGuard, Mapper, MapError, Entry, EntryFlags, Page, Table, TableLevel4, TableLevel5, TableLevelHeirarchy,
+    InactivePageTable, ENTRY_COUNT, tlb,
+};
+
+impl Mapper {
+    /// Clones this address space, sharing its user-half pages copy-on-write.
+    ///
+    /// Kernel-half entries of the P4 table are copied as they are, so the kernel half stays
+    /// shared. Huge pages in the user half can't be shared copy-on-write, so the clone fails if
+    /// there are any.
+    pub fn clone_copy_on_write<A>(&mut self, allocator: &mut A) -> Result<InactivePageTable, MapError>
+        where A: FrameAllocator
+    {
+        let _edit = EditGuard::new();
+        let p4_frame = allocator.alloc()
+            .ok_or(MapError::OutOfFrames)?;
+        let mut new_table = InactivePageTable::new_direct(p4_frame, allocator)?;
+        {
+            let new_p4 = direct_p4(&new_table);
+            // the kernel half is shared by every address space; the recursive entry was already set
+            for index in kernel_p4_index() .. ENTRY_COUNT - 1 {
+                new_p4[index] = self.p4()[index].clone();
+            }
+        }
+
+        if let Err(error) = self.share_user_half(&new_table, allocator) {
+            release_user_half(&mut new_table, allocator);
+            new_table.dealloc_p5(allocator);
+            allocator.dealloc(new_table.p4_frame);
+            return Err(error);
+        }
+
+        // pages in this address space may have just become read-only
+        tlb::flush_all();
+        Ok(new_table)
+    }
+
+    /// Copies the user half of this address space's page tables into `new_table`, sharing every
+    /// frame.
+    fn share_user_half<A>(&mut self, new_table: &InactivePageTable, allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        share_p4(self.p4_mut(), direct_p4(new_table), kernel_p4_index(), allocator)?;
+
+        // with five-level paging, the rest of the lower half of the P5 table has P4 tables of its
+        // own
+        let new_p5 = match direct_p5(new_table) {
+            Some(new_p5) => new_p5,
+            None => return Ok(()),
+        };
+        let p5 = self.p5_mut()
+            .expect("Five-level page tables can only be cloned through the physical map");
+        for p5_index in 1 .. ENTRY_COUNT / 2 {
+            let p5_flags = p5[p5_index].flags();
+            let p4 = match p5.next_table_mut(p5_index) {
+                Some(p4) => p4,
+                None => continue,
+            };
+            let new_p4 = new_table(new_p5, p5_index, p5_flags, allocator)?;
+            share_p4(p4, new_p4, ENTRY_COUNT, allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Gives a page its own writable copy of a copy-on-write frame.
+    ///
+    /// If no other address space shares the frame anymore, the page is simply made writable.
+    /// Returns `Ok(false)` if the page isn't copy-on-write.
+    pub fn copy_on_write<A>(&mut self, page: Page, allocator: &mut A) -> Result<bool, MapError>
+        where A: FrameAllocator
+    {
+        let _edit = EditGuard::new();
+        let p1 = self.p4_for_mut(page.p5_index())?
+            .try_next_table_mut(page.p4_index())?
+            .try_next_table_mut(page.p3_index())?
+            .try_next_table_mut(page.p2_index())?;
+        let entry = &mut p1[page.p1_index()];
+        let flags = entry.flags();
+        if !flags.contains(EntryFlags::PRESENT | EntryFlags::COPY_ON_WRITE) {
+            return Ok(false);
+        }
+
+        let frame = entry.to_frame().unwrap();
+        let new_flags = (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE;
+        if frame.ref_count() <= 1 {
+            frame.info().remove_flags(FrameFlags::COPY_ON_WRITE);
+            entry.set_flags(new_flags);
+        } else {
+            let copy = allocator.alloc()
+                .ok_or(MapError::OutOfFrames)?;
+            unsafe {
+                let source = frame.virtual_address().expect("Copy-on-write needs the physical map");
+                let destination = copy.virtual_address().expect("Copy-on-write needs the physical map");
+                ptr::copy_nonoverlapping(source as *const u8, destination as *mut u8, PAGE_SIZE);
+            }
+            entry.set(copy, new_flags);
+            frame.dec_ref(allocator);
+        }
+
+        tlb::flush(page.start_address());
+        Ok(true)
+    }
+}
+
+/// Gets the index of the first P4 entry of the kernel half.
+pub (in memory) fn kernel_p4_index() -> usize {
+    Page::containing_address(KERNEL_BASE).p4_index()
+}
+
+/// Gets whether the frame's references are counted, i.e. it was handed out by the frame
+/// allocator.
+fn is_counted(frame: &Frame) -> bool {
+    FrameInfo::get(frame)
+        .map(|info| info.ref_count() > 0)
+        .unwrap_or(false)
+}
+
+/// Copies the first `count` entries of `p4` into `new_p4`, along with every page table below
+/// them, sharing every frame.
+fn share_p4<A>(p4: &mut Table<TableLevel4>, new_p4: &mut Table<TableLevel4>, count: usize, allocator: &mut A)
+    -> Result<(), MapError>
+    where A: FrameAllocator
+{
+    for p4_index in 0 .. count {
+        let p4_flags = p4[p4_index].flags();
+        let p3 = match p4.next_table_mut(p4_index) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        let new_p3 = new_table(new_p4, p4_index, p4_flags, allocator)?;
+        for p3_index in 0 .. ENTRY_COUNT {
+            let p3_flags = p3[p3_index].flags();
+            if is_huge(p3_flags) {
+                return Err(MapError::HugePageInTheWay);
+            }
+            let p2 = match p3.next_table_mut(p3_index) {
+                Some(p2) => p2,
+                None => continue,
+            };
+            let new_p2 = new_table(new_p3, p3_index, p3_flags, allocator)?;
+            for p2_index in 0 .. ENTRY_COUNT {
+                let p2_flags = p2[p2_index].flags();
+                if is_huge(p2_flags) {
+                    return Err(MapError::HugePageInTheWay);
+                }
+                let p1 = match p2.next_table_mut(p2_index) {
+                    Some(p1) => p1,
+                    None => continue,
+                };
+                let new_p1 = new_table(new_p2, p2_index, p2_flags, allocator)?;
+                for p1_index in 0 .. ENTRY_COUNT {
+                    share_entry(&mut p1[p1_index]);
+                    new_p1[p1_index] = p1[p1_index].clone();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets whether an entry with the given flags maps a huge page.
+fn is_huge(flags: EntryFlags) -> bool {
+    flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE)
+}
+
+/// Gets the P4 table of an inactive page table through the physical map.
+fn direct_p4(table: &InactivePageTable) -> &'static mut Table<TableLevel4> {
+    let address = table.p4_frame.virtual_address()
+        .expect("Inactive page tables can only be edited directly through the physical map");
+    unsafe { &mut *(address as *mut Table<TableLevel4>) }
+}
+
+/// Gets the P5 table of an inactive page table through the physical map, if it has one.
+fn direct_p5(table: &InactivePageTable) -> Option<&'static mut Table<TableLevel5>> {
+    table.p5_frame.as_ref()
+        .map(|frame| {
+            let address = frame.virtual_address()
+                .expect("Inactive page tables can only be edited directly through the physical map");
+            unsafe { &mut *(address as *mut Table<TableLevel5>) }
+        })
+}
+
+/// Creates an empty page table at `index` of `table`, with the same flags as the entry that it
+/// is copied from.
+fn new_table<'a, L, A>(table: &'a mut Table<L>, index: usize, flags: EntryFlags, allocator: &mut A)
+    -> Result<&'a mut Table<L::NextLevel>, MapError>
+    where L: TableLevelHeirarchy,
+          A: FrameAllocator
+{
+    table.next_table_try_create(index, allocator)?;
+    table[index].set_flags(flags);
+    Ok(table.next_table_mut(index).unwrap())
+}
+
+/// Prepares a P1 entry to be shared with another address space.
+///
+/// Writable pages become read-only and copy-on-write, and the frame gets another reference.
+/// Frames that aren't counted (like memory-mapped devices) are shared as they are.
+fn share_entry(entry: &mut Entry) {
+    let frame = match entry.to_frame() {
+        Some(frame) => frame,
+        None => return,
+    };
+    if !is_counted(&frame) {
+        return;
+    }
+    let flags = entry.flags();
+    if flags.intersects(EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE) {
+        entry.set_flags((flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE);
+        frame.info().insert_flags(FrameFlags::COPY_ON_WRITE);
+    }
+    frame.inc_ref();
+}
+
+/// Releases every page table and frame that the user half of an inactive page table refers to,
+/// and clears the user half.
+///
+/// Frames that aren't counted are left alone. Huge pages are unmapped, but their frames are left
+/// alone too, since their references aren't counted per mapping. The P4 and P5 tables themselves
+/// are kept.
+pub (in memory) fn release_user_half<A>(table: &mut InactivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    release_p4(direct_p4(table), kernel_p4_index(), allocator);
+    if let Some(p5) = direct_p5(table) {
+        for p5_index in 1 .. ENTRY_COUNT / 2 {
+            if let Some(p4) = p5.next_table_mut(p5_index) {
+                release_p4(p4, ENTRY_COUNT, allocator);
+            }
+            release_table(&mut p5[p5_index], allocator);
+        }
+    }
+}
+
+/// Releases every page table and frame that the first `count` entries of `p4` refer to, and
+/// clears those entries.
+fn release_p4<A>(p4: &mut Table<TableLevel4>, count: usize, allocator: &mut A)
+    where A: FrameAllocator
+{
+    for p4_index in 0 .. count {
+        if let Some(p3) = p4.next_table_mut(p4_index) {
+            for p3_index in 0 .. ENTRY_COUNT {
+                if let Some(p2) = p3.next_table_mut(p3_index) {
+                    for p2_index in 0 .. ENTRY_COUNT {
+                        if let Some(p1) = p2.next_table_mut(p2_index) {
+                            for p1_index in 0 .. ENTRY_COUNT {
+                                release_entry(&mut p1[p1_index], allocator);
+                            }
+                        }
+                        release_table(&mut p2[p2_index], allocator);
+                    }
+                }
+                release_table(&mut p3[p3_index], allocator);
+            }
+        }
+        release_table(&mut p4[p4_index], allocator);
+    }
+}
+
+/// Clears a P1 entry, releasing this mapping's reference to its frame.
+fn release_entry<A>(entry: &mut Entry, allocator: &mut A)
+    where A: FrameAllocator
+{
+    if let Some(frame) = entry.to_frame() {
+        if is_counted(&frame) {
+            frame.dec_ref(allocator);
+        }
+    }
+    entry.set_unused();
+}
+
+/// Clears an entry that points to a page table or a huge page, giving back the page table.
+fn release_table<A>(entry: &mut Entry, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let flags = entry.flags();
+    if flags.contains(EntryFlags::PRESENT) && !flags.contains(EntryFlags::HUGE) {
+        allocator.dealloc(entry.to_frame().unwrap());
+    }
+    entry.set_unused();
+}
@@ -1,11 +1,16 @@
-use multiboot2::ElfSectionFlags;
+use boot_info::ElfSectionFlags;
 use memory::Frame;
 
+/// The bit that selects the upper half of the page attribute table in huge page entries, where
+/// bit 7 is taken by `HUGE`.
+const HUGE_PAT: u64 = 1 << 12;
+
 /// An entry in a page table.
 ///
 /// Page table entries for x86_64 are 64 bits wide.
 ///
 /// TODO(arch) this is x86_64 specific
+#[derive(Clone)]
 pub struct Entry(u64);
 
 impl Entry {
@@ -32,6 +37,29 @@ impl Entry {
         }
     }
 
+    /// Gets the first physical frame of the huge page that this entry maps, if it is present.
+    ///
+    /// Unlike `to_frame`, this leaves out bit 12, which is the PAT bit in huge page entries.
+    pub fn to_huge_frame(&self) -> Option<Frame> {
+        if self.flags().contains(EntryFlags::PRESENT) {
+            let addr = self.0 as usize & 0x000fffff_ffffe000;
+            Some(Frame::containing_address(addr))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the flags of this huge page entry, as they would be written in a P1 entry.
+    ///
+    /// `HUGE` is left out, and `PAT` is set if bit 12 is.
+    pub fn huge_flags(&self) -> EntryFlags {
+        let mut flags = self.flags() - EntryFlags::HUGE;
+        if self.0 & HUGE_PAT != 0 {
+            flags |= EntryFlags::PAT;
+        }
+        flags
+    }
+
     /// Sets the frame and flags for this entry.
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address() & !0x000f_ffff_ffff_f000 == 0,
@@ -39,14 +67,30 @@ impl Entry {
         self.0 = (frame.start_address() as u64) | flags.bits();
     }
 
+    /// Sets the frame and flags for this huge page entry, taking the flags as they would be written
+    /// in a P1 entry.
+    ///
+    /// `HUGE` is added, and `PAT` is moved to bit 12.
+    pub fn set_huge(&mut self, frame: Frame, flags: EntryFlags) {
+        let pat = if flags.contains(EntryFlags::PAT) { HUGE_PAT } else { 0 };
+        self.set(frame, flags | EntryFlags::HUGE);
+        self.0 |= pat;
+    }
+
     /// Sets the flags for this entry, overwriting any flags that were previously set.
     ///
     /// Note that this will overwrite the "PRESENT" flag if not accounted for.
     pub fn set_flags(&mut self, flags: EntryFlags) {
         let addr = (!self.flags().bits()) & self.0;
-        vgaprintln!("address {:#x} self.0 {:#x}", addr, self.0);
         self.0 = flags.bits() | addr;
-        vgaprintln!("address {:#x} self.0 {:#x}", addr, self.0);
+    }
+
+    /// Replaces the protection flags (`WRITABLE`, `USER` and `NOEXEC`) of this entry with the ones
+    /// in `flags`, leaving every other flag alone.
+    pub fn set_protection(&mut self, flags: EntryFlags) {
+        let mask = EntryFlags::WRITABLE | EntryFlags::USER | EntryFlags::NOEXEC;
+        let new_flags = (self.flags() - mask) | (flags & mask);
+        self.set_flags(new_flags);
     }
 }
 
@@ -61,12 +105,55 @@ bitflags! {
         const ACCESSED      = 1 << 5;
         const DIRTY         = 1 << 6;
         const HUGE          = 1 << 7;
+        /// Selects the upper half of the page attribute table. This is the same bit as `HUGE`,
+        /// and only means this in P1 entries.
+        const PAT           = 1 << 7;
         const GLOBAL        = 1 << 8;
         // bits 9-11 and 52-62 are unused by the CPU
+        /// The page is shared with another address space and must be copied before it is
+        /// written to.
+        const COPY_ON_WRITE = 1 << 9;
         const NOEXEC        = 1 << 63;
     }
 }
 
+/// How the CPU caches accesses to a page.
+///
+/// TODO(arch) this is x86_64 specific
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Every access goes straight to memory, in order. This is what device registers want.
+    Uncacheable,
+    /// Writes are buffered and combined, and reads aren't cached. This is what framebuffers want.
+    WriteCombining,
+    /// Reads are cached, but writes go straight to memory as well.
+    WriteThrough,
+    /// Reads and writes are cached. This is what normal memory uses.
+    WriteBack,
+}
+
+impl MemoryType {
+    /// Gets the flags that select this memory type in a P1 entry.
+    ///
+    /// Huge page entries keep `PAT` in bit 12 instead, so these must go through `Entry::set_huge`
+    /// for huge pages, which moves it there.
+    ///
+    /// These index into the page attribute table that `arch::x86_64::init_pat` programs. If it
+    /// couldn't be programmed, write-combining falls back to uncacheable.
+    pub fn flags(&self) -> EntryFlags {
+        // TODO(arch) abstract away x86_64 calls
+        use arch::x86_64::pat_enabled;
+
+        match *self {
+            MemoryType::Uncacheable => EntryFlags::WRITETHROUGH | EntryFlags::DISABLECACHE,
+            MemoryType::WriteCombining if pat_enabled() => EntryFlags::PAT,
+            MemoryType::WriteCombining => EntryFlags::WRITETHROUGH | EntryFlags::DISABLECACHE,
+            MemoryType::WriteThrough => EntryFlags::WRITETHROUGH,
+            MemoryType::WriteBack => EntryFlags::empty(),
+        }
+    }
+}
+
 impl From<ElfSectionFlags> for EntryFlags {
     fn from(section: ElfSectionFlags) -> Self {
         let mut flags = EntryFlags::empty();
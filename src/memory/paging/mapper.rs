@@ -1,47 +1,247 @@
+use core::fmt;
 use core::ptr::Unique;
-use memory::frame::{Frame, FrameAllocator};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory::frame::{Frame, FrameAllocator, FrameInfo};
+use memory::map::KERNEL_BASE;
 use memory::paging::*;
+use memory::paging::tlb::{self, TlbBatch};
+
+/// The number of page table edits that are under way, through any mapper.
+static EDITS_IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets whether a page table is being edited through some mapper right now.
+///
+/// See `ActivePageTable::new` for why this matters.
+pub (in memory) fn page_table_edit_in_progress() -> bool {
+    EDITS_IN_PROGRESS.load(Ordering::SeqCst) != 0
+}
+
+/// Marks a page table edit as under way for as long as it lives.
+pub (in memory) struct EditGuard;
+
+impl EditGuard {
+    pub (in memory) fn new() -> Self {
+        EDITS_IN_PROGRESS.fetch_add(1, Ordering::SeqCst);
+        EditGuard
+    }
+}
+
+impl Drop for EditGuard {
+    fn drop(&mut self) {
+        EDITS_IN_PROGRESS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An error that occurred while changing the mappings of a page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// There were no frames left to map the page or to create a page table with.
+    OutOfFrames,
+    /// A huge page is mapped where a page table was expected.
+    HugePageInTheWay,
+    /// The page is not mapped.
+    NotMapped,
+    /// There was no virtual memory left to map the page into.
+    OutOfVirtualMemory,
+    /// The frame is not aligned to the size of the page that it is mapped with.
+    MisalignedFrame,
+    /// The CPU doesn't support pages of the requested size.
+    UnsupportedPageSize,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapError::AlreadyMapped => write!(f, "page is already mapped"),
+            MapError::OutOfFrames => write!(f, "no available frames"),
+            MapError::HugePageInTheWay => write!(f, "a huge page is in the way"),
+            MapError::NotMapped => write!(f, "page is not mapped"),
+            MapError::OutOfVirtualMemory => write!(f, "no available virtual memory"),
+            MapError::MisalignedFrame => write!(f, "frame is not aligned to the page size"),
+            MapError::UnsupportedPageSize => write!(f, "page size is not supported by the CPU"),
+        }
+    }
+}
+
 pub struct Mapper {
     p4: Unique<Table<TableLevel4>>,
+
+    /// The P5 table of an inactive page table, if five-level paging is enabled. The active page
+    /// table's P5 table is found through CR3 instead.
+    p5: Option<Unique<Table<TableLevel5>>>,
 }
 
 impl Mapper {
     pub unsafe fn new() -> Self {
         Mapper {
             p4: Unique::new_unchecked(P4),
+            p5: None,
         }
     }
 
-    /// Gets a reference to the top-level page table.
+    /// Creates a mapper for the P4 table at the given address, and the P5 table that is folded
+    /// onto it if five-level paging is enabled.
+    ///
+    /// Tables below the P4 table are reached through the physical map, so it must be set up.
+    pub (in memory) unsafe fn from_tables(p4: *mut Table<TableLevel4>, p5: Option<*mut Table<TableLevel5>>) -> Self {
+        assert!(physical_map_enabled(), "Page tables can only be walked directly through the physical map");
+        Mapper {
+            p4: Unique::new_unchecked(p4),
+            p5: p5.map(|p5| Unique::new_unchecked(p5)),
+        }
+    }
+
+    /// Gets a reference to the P4 table that the P5 table is folded onto, which maps the lower
+    /// and upper 128 TiB of the address space.
+    ///
+    /// Without five-level paging, this is the top-level page table.
     pub (in memory) fn p4(&self) -> &Table<TableLevel4> {
         unsafe { self.p4.as_ref() }
     }
 
-    /// Gets the top-level page table mutably.
+    /// Gets the P4 table that the P5 table is folded onto mutably.
     pub (in memory) fn p4_mut(&mut self) -> &mut Table<TableLevel4> {
         unsafe { self.p4.as_mut() }
     }
 
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A)
+    /// Gets a reference to the P5 table, if five-level paging is enabled and the physical map is
+    /// set up.
+    pub (in memory) fn p5(&self) -> Option<&Table<TableLevel5>> {
+        self.p5_address()
+            .map(|address| unsafe { &*(address as *const Table<TableLevel5>) })
+    }
+
+    /// Gets the P5 table mutably, if five-level paging is enabled and the physical map is set up.
+    pub (in memory) fn p5_mut(&mut self) -> Option<&mut Table<TableLevel5>> {
+        self.p5_address()
+            .map(|address| unsafe { &mut *(address as *mut Table<TableLevel5>) })
+    }
+
+    /// Gets the address of the P5 table.
+    fn p5_address(&self) -> Option<VirtualAddress> {
+        // TODO(arch) abstract away x86_64 calls
+        use arch::x86_64::la57_enabled;
+        use x86_64::registers::control_regs;
+
+        if let Some(p5) = self.p5 {
+            return Some(p5.as_ptr() as VirtualAddress);
+        }
+        if !la57_enabled() || self.p4.as_ptr() != P4 {
+            return None;
+        }
+        Frame::containing_address(control_regs::cr3().0 as usize).virtual_address()
+    }
+
+    /// Gets the P4 table that maps addresses with the given P5 index.
+    ///
+    /// Entries 0 and 511 of the P5 table are folded onto `p4`. The other entries only exist with
+    /// five-level paging, and their P4 tables are reached through the physical map.
+    pub (in memory) fn p4_for(&self, p5_index: usize) -> Result<&Table<TableLevel4>, MapError> {
+        match p5_index {
+            0 | 511 => Ok(self.p4()),
+            index => self.p5()
+                .ok_or(MapError::NotMapped)?
+                .try_next_table(index),
+        }
+    }
+
+    /// Gets the P4 table that maps addresses with the given P5 index mutably.
+    pub (in memory) fn p4_for_mut(&mut self, p5_index: usize) -> Result<&mut Table<TableLevel4>, MapError> {
+        match p5_index {
+            0 | 511 => Ok(self.p4_mut()),
+            index => self.p5_mut()
+                .ok_or(MapError::NotMapped)?
+                .try_next_table_mut(index),
+        }
+    }
+
+    /// Gets the P4 table that maps addresses with the given P5 index mutably, creating it if it
+    /// doesn't exist yet.
+    fn p4_for_create<A>(&mut self, p5_index: usize, allocator: &mut A) -> Result<&mut Table<TableLevel4>, MapError>
         where A: FrameAllocator
     {
-        // make sure that this page is actually mapped
-        assert!(self.translate(page.start_address()).is_some());
-
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("Hugepages are not supported yet");
-        let _frame = p1[page.p1_index()].to_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        // TODO : de-allocate above page frames if they're empty
-        // TODO(arch) abstract away x86_64 calls
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
-        tlb::flush(VirtualAddress(page.start_address()));
-        // TODO(dealloc)
-        //allocator.dealloc(frame);
+        match p5_index {
+            0 | 511 => Ok(self.p4_mut()),
+            index => self.p5_mut()
+                .ok_or(MapError::NotMapped)?
+                .next_table_try_create(index, allocator),
+        }
+    }
+
+    /// Unmaps a page, returning the frame that it was mapped to.
+    ///
+    /// Page tables that no longer map anything are given back to the allocator, but the frame
+    /// itself is not freed; use `unmap_and_free` for that.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator
+    {
+        self.try_unmap(page, allocator)
+            .unwrap_or_else(|error| panic!("Could not unmap page {:#x}: {}", page.start_address(), error))
+    }
+
+    /// Unmaps a page, returning the frame that it was mapped to, or an error if it can't be
+    /// unmapped.
+    pub fn try_unmap<A>(&mut self, page: Page, allocator: &mut A) -> Result<Frame, MapError>
+        where A: FrameAllocator
+    {
+        let _edit = EditGuard::new();
+        let frame = {
+            let p1 = self.p4_for_mut(page.p5_index())?
+                .try_next_table_mut(page.p4_index())?
+                .try_next_table_mut(page.p3_index())?
+                .try_next_table_mut(page.p2_index())?;
+            let frame = p1[page.p1_index()].to_frame()
+                .ok_or(MapError::NotMapped)?;
+            p1[page.p1_index()].set_unused();
+            frame
+        };
+        tlb::flush(page.start_address());
+
+        self.free_empty_tables(page, allocator);
+        Ok(frame)
+    }
+
+    /// Unmaps a page and releases the frame that it was mapped to.
+    ///
+    /// If the frame is shared, only this mapping's reference to it is dropped.
+    pub fn unmap_and_free<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let frame = self.unmap(page, allocator);
+        match FrameInfo::get(&frame) {
+            Some(info) if info.ref_count() > 0 => { frame.dec_ref(allocator); },
+            // frames from before the metadata table was set up aren't reference counted
+            _ => allocator.dealloc(frame),
+        }
+    }
+
+    /// Gives back the page tables on the way to `page` that no longer map anything.
+    ///
+    /// Every level is checked, even if the table below it wasn't freed, so that tables which were
+    /// created but never filled are given back too. P4 tables are never freed.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let p4 = match self.p4_for_mut(page.p5_index()) {
+            Ok(p4) => p4,
+            Err(_) => return,
+        };
+        let mut freed = false;
+        if let Some(p2) = p4.next_table_mut(page.p4_index()).and_then(|p3| p3.next_table_mut(page.p3_index())) {
+            freed |= p2.free_next_table_if_empty(page.p2_index(), allocator);
+        }
+        if let Some(p3) = p4.next_table_mut(page.p4_index()) {
+            freed |= p3.free_next_table_if_empty(page.p3_index(), allocator);
+        }
+        if page.start_address() < KERNEL_BASE {
+            p4.free_next_table_if_empty(page.p4_index(), allocator);
+        } else if freed {
+            // Kernel P3 tables are shared by every address space, so they must stay put. The tables
+            // below them may still be cached under other PCIDs, which `invlpg` doesn't reach.
+            tlb::flush_everything();
+        }
     }
 
     /// Convenience function that identity maps a frame.
@@ -52,25 +252,285 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Identity maps a frame, or returns an error if it can't be mapped.
+    pub fn try_identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let page = Page::containing_address(frame.start_address());
+        self.try_map_to(page, frame, flags, allocator)
+    }
+
     /// Maps a page to a yet-to-be-allocated frame.
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
-        let frame = allocator.alloc().expect("No available frames");
-        self.map_to(page, frame, flags, allocator)
+        self.try_map(page, flags, allocator)
+            .unwrap_or_else(|error| panic!("Could not map page {:#x}: {}", page.start_address(), error))
+    }
+
+    /// Maps a page to a yet-to-be-allocated frame, or returns an error if it can't be mapped.
+    ///
+    /// If mapping fails, the newly allocated frame is given back to the allocator.
+    pub fn try_map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let frame = allocator.alloc()
+            .ok_or(MapError::OutOfFrames)?;
+        if let Err(error) = self.try_map_to(page, frame.clone(), flags, allocator) {
+            allocator.dealloc(frame);
+            return Err(error);
+        }
+        Ok(())
     }
 
     /// Maps a page to a given frame.
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
-        let p4 = self.p4_mut();
-        let p3 = p4.next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        self.try_map_to(page, frame, flags, allocator)
+            .unwrap_or_else(|error| panic!("Could not map page {:#x}: {}", page.start_address(), error))
+    }
+
+    /// Maps a page to a given frame, or returns an error if it can't be mapped.
+    ///
+    /// Page tables that were created on the way are given back if mapping fails.
+    pub fn try_map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let _edit = EditGuard::new();
+        if let Err(error) = self.try_set_entry(page, frame, flags, allocator) {
+            self.free_empty_tables(page, allocator);
+            return Err(error);
+        }
+
+        // user pages are only reachable if every table on the way is
+        if flags.contains(EntryFlags::USER) {
+            if let Some(p5) = self.p5_mut() {
+                grant_user(&mut p5[page.p5_index()]);
+            }
+            let p4 = self.p4_for_mut(page.p5_index()).unwrap();
+            grant_user(&mut p4[page.p4_index()]);
+            let p3 = p4.next_table_mut(page.p4_index()).unwrap();
+            grant_user(&mut p3[page.p3_index()]);
+            let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+            grant_user(&mut p2[page.p2_index()]);
+        }
+        Ok(())
+    }
+
+    /// Sets the P1 entry of a page, creating the page tables on the way as needed.
+    fn try_set_entry<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let p1 = self.p4_for_create(page.p5_index(), allocator)?
+            .next_table_try_create(page.p4_index(), allocator)?
+            .next_table_try_create(page.p3_index(), allocator)?
+            .next_table_try_create(page.p2_index(), allocator)?;
+
+        if p1[page.p1_index()].is_used() {
+            return Err(MapError::AlreadyMapped);
+        }
+        p1[page.p1_index()].set(frame, global_if_kernel(page.start_address(), flags) | EntryFlags::PRESENT);
+        Ok(())
+    }
+
+    /// Maps a huge page to a given frame, which must be aligned to the size of the page.
+    ///
+    /// The frames that follow `frame` are mapped by the same page, so they must all be available.
+    /// `flags` are given as they would be for a 4 KiB page, so they can include
+    /// `MemoryType::flags`, but must not include `HUGE`.
+    pub fn map_to_huge<S, A>(&mut self, page: Page<S>, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        where S: HugePageSize,
+              A: FrameAllocator
+    {
+        self.try_map_to_huge(page, frame, flags, allocator)
+            .unwrap_or_else(|error| panic!("Could not map huge page {:#x}: {}", page.start_address(), error))
+    }
+
+    /// Maps a huge page to a given frame, or returns an error if it can't be mapped.
+    ///
+    /// Page tables that were created on the way are given back if mapping fails.
+    pub fn try_map_to_huge<S, A>(&mut self, page: Page<S>, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where S: HugePageSize,
+              A: FrameAllocator
+    {
+        // TODO(arch) abstract away x86_64 calls
+        use arch::x86_64::cpu::features;
+
+        let _edit = EditGuard::new();
+        if frame.start_address() % S::SIZE != 0 {
+            return Err(MapError::MisalignedFrame);
+        }
+        if S::LEVEL == 3 && !features::has_1gib_pages() {
+            return Err(MapError::UnsupportedPageSize);
+        }
+        let result = self.try_set_huge_entry(page, frame, flags, allocator);
+        if result.is_err() {
+            self.free_empty_tables(Page::containing_address(page.start_address()), allocator);
+        }
+        result
+    }
 
-        assert!(!p1[page.p1_index()].is_used(), "Attempted to use a page that is already in use: page #{:#x}", page.p1_index());
-        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+    /// Sets the P2 or P3 entry of a huge page, creating the page tables on the way as needed.
+    fn try_set_huge_entry<S, A>(&mut self, page: Page<S>, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where S: HugePageSize,
+              A: FrameAllocator
+    {
+        let flags = global_if_kernel(page.start_address(), flags) | EntryFlags::PRESENT;
+
+        let p3 = self.p4_for_create(page.p5_index(), allocator)?
+            .next_table_try_create(page.p4_index(), allocator)?;
+        let entry = match S::LEVEL {
+            2 => {
+                let p2 = p3.next_table_try_create(page.p3_index(), allocator)?;
+                &mut p2[page.p2_index()]
+            },
+            3 => &mut p3[page.p3_index()],
+            level => unreachable!("Invalid huge page level: {}", level),
+        };
+        if entry.is_used() {
+            return Err(MapError::AlreadyMapped);
+        }
+        entry.set_huge(frame, flags);
+        Ok(())
+    }
+
+    /// Unmaps a huge page, returning the first frame that it was mapped to.
+    ///
+    /// Like `unmap`, page tables that no longer map anything are given back to the allocator.
+    pub fn unmap_huge<S, A>(&mut self, page: Page<S>, allocator: &mut A) -> Frame
+        where S: HugePageSize,
+              A: FrameAllocator
+    {
+        self.try_unmap_huge(page, allocator)
+            .unwrap_or_else(|error| panic!("Could not unmap huge page {:#x}: {}", page.start_address(), error))
+    }
+
+    /// Unmaps a huge page, returning the first frame that it was mapped to, or an error if it
+    /// can't be unmapped.
+    pub fn try_unmap_huge<S, A>(&mut self, page: Page<S>, allocator: &mut A) -> Result<Frame, MapError>
+        where S: HugePageSize,
+              A: FrameAllocator
+    {
+        let _edit = EditGuard::new();
+        let frame = {
+            let p3 = self.p4_for_mut(page.p5_index())?
+                .try_next_table_mut(page.p4_index())?;
+            let entry = match S::LEVEL {
+                2 => &mut p3.try_next_table_mut(page.p3_index())?[page.p2_index()],
+                3 => &mut p3[page.p3_index()],
+                level => unreachable!("Invalid huge page level: {}", level),
+            };
+            if !entry.flags().contains(EntryFlags::HUGE) {
+                return Err(MapError::NotMapped);
+            }
+            let frame = entry.to_huge_frame()
+                .ok_or(MapError::NotMapped)?;
+            entry.set_unused();
+            frame
+        };
+        tlb::flush(page.start_address());
+
+        self.free_empty_tables(Page::containing_address(page.start_address()), allocator);
+        Ok(frame)
+    }
+
+    /// Changes the protection flags of every page in the given range.
+    ///
+    /// Only the `WRITABLE`, `USER` and `NOEXEC` flags are replaced by the ones in `flags`. Huge
+    /// pages that only partially overlap the range are split into smaller pages first, which may
+    /// allocate page tables. If `flags` contains `USER`, the page table entries above each page
+    /// are made accessible to user mode as well.
+    ///
+    /// If an error is returned, the pages before the one that failed have already been changed.
+    ///
+    /// # Arguments
+    /// `start` - the page-aligned address to start at.
+    /// `end` - the page-aligned address to stop at (exclusive).
+    /// `flags` - the new protection flags.
+    /// `allocator` - the allocator to get page tables from when huge pages need to be split.
+    pub fn protect<A>(&mut self, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+                "Protected range {:#x} - {:#x} is not page-aligned", start, end);
+        let _edit = EditGuard::new();
+        // the pages that were changed are flushed together once the batch is dropped, even if one
+        // of them fails
+        let mut batch = TlbBatch::new();
+        let mut address = start;
+        while address < end {
+            address += self.protect_page(Page::containing_address(address), end, flags, &mut batch, allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Changes the protection flags of whatever maps `page`, splitting it first if it is a huge
+    /// page that reaches outside of `page.start_address() .. end`.
+    ///
+    /// Returns the size of the page whose flags were changed. Pages that need to be flushed are
+    /// added to `batch`.
+    fn protect_page<A>(&mut self, page: Page, end: VirtualAddress, flags: EntryFlags, batch: &mut TlbBatch,
+                       allocator: &mut A)
+        -> Result<usize, MapError>
+        where A: FrameAllocator
+    {
+        let address = page.start_address();
+        let covers = |size: usize| address % size == 0 && end - address >= size;
+        let user = flags.contains(EntryFlags::USER);
+
+        if user {
+            if let Some(p5) = self.p5_mut() {
+                grant_user(&mut p5[page.p5_index()]);
+            }
+        }
+        let p4 = self.p4_for_mut(page.p5_index())?;
+        if user {
+            grant_user(&mut p4[page.p4_index()]);
+        }
+        let p3 = p4.try_next_table_mut(page.p4_index())?;
+
+        if p3[page.p3_index()].flags().contains(EntryFlags::HUGE) {
+            if covers(Size1GiB::SIZE) {
+                p3[page.p3_index()].set_protection(flags);
+                batch.add(address);
+                return Ok(Size1GiB::SIZE);
+            }
+            split_huge_page(p3, page.p3_index(), ENTRY_COUNT, allocator)?;
+            batch.add(address);
+        }
+        if user {
+            grant_user(&mut p3[page.p3_index()]);
+        }
+        let p2 = p3.try_next_table_mut(page.p3_index())?;
+
+        if p2[page.p2_index()].flags().contains(EntryFlags::HUGE) {
+            if covers(Size2MiB::SIZE) {
+                p2[page.p2_index()].set_protection(flags);
+                batch.add(address);
+                return Ok(Size2MiB::SIZE);
+            }
+            split_huge_page(p2, page.p2_index(), 1, allocator)?;
+            batch.add(address);
+        }
+        if user {
+            grant_user(&mut p2[page.p2_index()]);
+        }
+        let p1 = p2.try_next_table_mut(page.p2_index())?;
+
+        let entry = &mut p1[page.p1_index()];
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            return Err(MapError::NotMapped);
+        }
+        entry.set_protection(flags);
+        batch.add(address);
+        Ok(PAGE_SIZE)
     }
 
     /// Translate a virtual address into a (possible) physical address.
@@ -82,7 +542,8 @@ impl Mapper {
 
     /// Converts a page to a (possible) frame that it points at.
     pub (in memory) fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+        let p3 = self.p4_for(page.p5_index()).ok()
+            .and_then(|p4| p4.next_table(page.p4_index()));
 
         // closure to help handle hugepages
         let huge_page = || {
@@ -90,7 +551,7 @@ impl Mapper {
                 let p3_entry = &p3[page.p3_index()];
 
                 // 1GB page?
-                if let Some(start_frame) = p3_entry.to_frame() {
+                if let Some(start_frame) = p3_entry.to_huge_frame() {
                     if p3_entry.flags().contains(EntryFlags::HUGE) {
                         assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
                         return Some(Frame {
@@ -101,7 +562,7 @@ impl Mapper {
                 if let Some(p2) = p3.next_table(page.p3_index()) {
                     let p2_entry = &p2[page.p2_index()];
                     // 2MB page?
-                    if let Some(start_frame) = p2_entry.to_frame() {
+                    if let Some(start_frame) = p2_entry.to_huge_frame() {
                         if p2_entry.flags().contains(EntryFlags::HUGE) {
                             assert!(start_frame.number % ENTRY_COUNT == 0);
                             return Some(Frame {
@@ -121,3 +582,69 @@ impl Mapper {
             .or_else(huge_page)
     }
 }
+
+/// Adds `GLOBAL` to the flags of kernel pages, so that they stay in the TLB across address space
+/// switches.
+fn global_if_kernel(address: VirtualAddress, flags: EntryFlags) -> EntryFlags {
+    // TODO(arch) abstract away x86_64 calls
+    use arch::x86_64::global_pages_enabled;
+
+    if address >= KERNEL_BASE && global_pages_enabled() {
+        flags | EntryFlags::GLOBAL
+    } else {
+        flags
+    }
+}
+
+/// Makes the page table entry that leads to a table accessible to user mode, if it is present.
+fn grant_user(entry: &mut Entry) {
+    let flags = entry.flags();
+    if flags.contains(EntryFlags::PRESENT) {
+        entry.set_flags(flags | EntryFlags::USER);
+    }
+}
+
+/// Replaces the huge page at `index` of `table` with a new page table that maps the same frames,
+/// using pages of the next size down.
+///
+/// # Arguments
+/// `table` - the P3 or P2 table that maps the huge page.
+/// `index` - the index of the huge page in `table`.
+/// `frames_per_entry` - the number of frames that each entry of the new table maps.
+/// `allocator` - the allocator to get the new page table from.
+fn split_huge_page<L, A>(table: &mut Table<L>, index: usize, frames_per_entry: usize, allocator: &mut A)
+    -> Result<(), MapError>
+    where L: TableLevelHeirarchy,
+          A: FrameAllocator
+{
+    let flags = table[index].huge_flags();
+    let start_frame = table[index].to_huge_frame()
+        .ok_or(MapError::NotMapped)?;
+    let fill = |new_table: &mut Table<L::NextLevel>| {
+        for i in 0 .. ENTRY_COUNT {
+            let frame = Frame { number: start_frame.number + i * frames_per_entry };
+            // P1 entries keep `PAT` where huge page entries keep `HUGE`
+            if frames_per_entry == 1 {
+                new_table[i].set(frame, flags);
+            } else {
+                new_table[i].set_huge(frame, flags);
+            }
+        }
+    };
+
+    let table_frame = allocator.alloc()
+        .ok_or(MapError::OutOfFrames)?;
+    let table_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | (flags & EntryFlags::USER);
+    match table_frame.virtual_address() {
+        // fill the new table in before it is put in place, so the pages are never unmapped
+        Some(address) => {
+            fill(unsafe { &mut *(address as *mut Table<L::NextLevel>) });
+            table[index].set(table_frame, table_flags);
+        },
+        None => {
+            table[index].set(table_frame, table_flags);
+            fill(table.next_table_mut(index).unwrap());
+        },
+    }
+    Ok(())
+}
@@ -1,119 +1,222 @@
+use core::fmt;
+use core::marker::PhantomData;
 use core::ops::Add;
-use multiboot2::BootInformation;
-use memory::{PAGE_SIZE, Frame, FrameAllocator, map::KERNEL_BASE};
+use boot_info::BootInfo;
+use memory::{
+    PAGE_SIZE, Frame, FrameAllocator, GuardPage, register_guard_page,
+    map::{
+        KERNEL_BASE, KERNEL_TEMPORARY_PAGE, KERNEL_VGA_BUFFER, USER_BASE, USER_END, USER_START_LA57,
+        USER_END_LA57,
+    },
+};
+// TODO(arch) abstract away x86_64 calls
+use arch::x86_64::la57_enabled;
 
 mod entry;
 mod table;
 mod temporary_page;
 mod mapper;
+mod physical_map;
+mod walker;
+mod cow;
+pub mod tlb;
+mod audit;
 
 pub use self::entry::*;
 pub use self::table::*;
 pub use self::temporary_page::*;
-pub use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapError};
+pub use self::physical_map::*;
+pub use self::walker::*;
+pub use self::cow::*;
+pub use self::audit::*;
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
+/// The size of a virtual page.
+pub trait PageSize: Copy + Clone + fmt::Debug {
+    /// The size of the page, in bytes.
+    const SIZE: usize;
+
+    /// The level of the page table whose entries map pages of this size.
+    const LEVEL: usize;
+}
+
+/// A page size that is mapped by an entry in a P2 or P3 table, rather than a P1 table.
+pub trait HugePageSize: PageSize {}
+
+/// A regular 4 KiB page.
+#[derive(Debug, Clone, Copy)]
+pub enum Size4KiB {}
+
+/// A 2 MiB huge page, mapped by a P2 entry.
+#[derive(Debug, Clone, Copy)]
+pub enum Size2MiB {}
+
+/// A 1 GiB huge page, mapped by a P3 entry.
+#[derive(Debug, Clone, Copy)]
+pub enum Size1GiB {}
+
+impl PageSize for Size4KiB {
+    const SIZE: usize = PAGE_SIZE;
+    const LEVEL: usize = 1;
+}
+
+impl PageSize for Size2MiB {
+    const SIZE: usize = PAGE_SIZE * ENTRY_COUNT;
+    const LEVEL: usize = 2;
+}
+
+impl PageSize for Size1GiB {
+    const SIZE: usize = PAGE_SIZE * ENTRY_COUNT * ENTRY_COUNT;
+    const LEVEL: usize = 3;
+}
+
+impl HugePageSize for Size2MiB {}
+impl HugePageSize for Size1GiB {}
+
 /// A virtual page of memory that maps to a physical frame.
+///
+/// Pages are 4 KiB unless another `PageSize` is given.
 #[derive(Debug, Clone, Copy)]
-pub struct Page {
+pub struct Page<S: PageSize = Size4KiB> {
     number: usize,
+    _size: PhantomData<S>,
 }
 
 impl Page {
-    pub fn range_inclusive(start: Page, end: Page) -> PageRangeIter {
-        PageRangeIter { start: start.number, end: end.number }
+    pub fn containing_address(address: VirtualAddress) -> Self {
+        assert_canonical(address);
+        Page { number: address / PAGE_SIZE, _size: PhantomData }
     }
+}
 
-    pub fn containing_address(address: VirtualAddress) -> Self {
-        assert!(address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000,
-                "invalid address passed to Page::containing_address: 0x{:x}", address);
-        Page { number: address / PAGE_SIZE }
+impl<S: PageSize> Page<S> {
+    pub fn range_inclusive(start: Page<S>, end: Page<S>) -> PageRangeIter<S> {
+        PageRangeIter { start: start.number, end: end.number, _size: PhantomData }
+    }
+
+    /// Gets the page that starts at the given address, which must be aligned to the page size.
+    pub fn from_start_address(address: VirtualAddress) -> Self {
+        assert_canonical(address);
+        assert!(address % S::SIZE == 0, "Page address {:#x} is not aligned to {:#x} bytes", address, S::SIZE);
+        Page { number: address / S::SIZE, _size: PhantomData }
     }
 
     pub fn start_address(&self) -> usize {
-        self.number * PAGE_SIZE
+        self.number * S::SIZE
+    }
+
+    /// Gets the index into the P5 table, which is 0 or 511 unless five-level paging is enabled.
+    pub fn p5_index(&self) -> usize {
+        (self.start_address() >> 48) & 0o777
     }
 
     pub fn p4_index(&self) -> usize {
-        (self.number >> 27) & 0o777
+        (self.start_address() >> 39) & 0o777
     }
 
     pub fn p3_index(&self) -> usize {
-        (self.number >> 18) & 0o777
+        (self.start_address() >> 30) & 0o777
     }
 
     pub fn p2_index(&self) -> usize {
-        (self.number >> 9) & 0o777
+        (self.start_address() >> 21) & 0o777
     }
 
     pub fn p1_index(&self) -> usize {
-        self.number & 0o777
+        (self.start_address() >> 12) & 0o777
     }
 }
 
-impl Add<usize> for Page {
-    type Output = Page;
+impl<S: PageSize> Add<usize> for Page<S> {
+    type Output = Page<S>;
 
-    fn add(self, rhs: usize) -> Page {
-        Page { number: self.number + rhs }
+    fn add(self, rhs: usize) -> Page<S> {
+        Page { number: self.number + rhs, _size: PhantomData }
     }
 }
 
+/// Gets the end of the lower half of the address space (exclusive), which is where user-space
+/// addresses end.
+///
+/// With five-level paging, addresses are 57 bits wide instead of 48, though not everything below
+/// this is user space; see `is_user_address`.
+pub fn user_end() -> VirtualAddress {
+    if la57_enabled() {
+        USER_END_LA57
+    } else {
+        USER_END
+    }
+}
+
+/// Gets whether the given address lies in user space.
+///
+/// With five-level paging, the addresses from `USER_END` to `USER_START_LA57` go through the P4
+/// entries of the kernel half (see `fold_p5`), so they are never user addresses.
+pub fn is_user_address(address: VirtualAddress) -> bool {
+    address >= USER_BASE && address < user_end()
+        && (address < USER_END || address >= USER_START_LA57)
+}
+
+/// Makes sure that the given address is canonical, i.e. its upper bits are sign-extended.
+fn assert_canonical(address: VirtualAddress) {
+    let lower_half_end = user_end();
+    assert!(address < lower_half_end || address >= !(lower_half_end - 1),
+            "invalid address passed to Page::containing_address: 0x{:x}", address);
+}
+
 #[derive(Debug, Clone)]
-pub struct PageRangeIter {
+pub struct PageRangeIter<S: PageSize = Size4KiB> {
     start: usize,
     end: usize,
+    _size: PhantomData<S>,
 }
 
-impl Iterator for PageRangeIter {
-    type Item = Page;
+impl<S: PageSize> Iterator for PageRangeIter<S> {
+    type Item = Page<S>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.start > self.end {
             None
         } else {
-            let page = Page { number: self.start };
+            let page = Page { number: self.start, _size: PhantomData };
             self.start += 1;
             Some(page)
         }
     }
 }
 
-pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
+pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInfo) -> ActivePageTable
     where A: FrameAllocator
 {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xDECAFDAD }, allocator);
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(KERNEL_TEMPORARY_PAGE), allocator);
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.alloc().expect("No frames available");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        InactivePageTable::new(frame, &mut active_table, &mut temporary_page, allocator)
     };
 
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        let elf_sections_tag = boot_info.elf_sections_tag()
-            .expect("ELF sections tag memory map not available");
-        for section in elf_sections_tag.sections() {
-            if !section.is_allocated() {
-                continue; // don't bother allocating unused sections
-            }
-            assert!(section.start_address() % PAGE_SIZE as u64 == 0,
+        // only allocated sections are kept in the boot information
+        for section in boot_info.elf_sections() {
+            assert!(section.start_address() % PAGE_SIZE == 0,
                     "ELF Sections must be page-aligned (got {:#x} instead)", section.start_address());
-            let flags = EntryFlags::from(section.flags().clone());
-            if section.start_address() > KERNEL_BASE as u64 {
+            let flags = EntryFlags::from(section.flags());
+            if section.start_address() > KERNEL_BASE {
                 vgaprintln!("Mapping ELF section from {:#x} - {:#x}", section.start_address(), section.end_address());
-                let start_page = Page::containing_address(section.start_address() as usize);
-                let end_page = Page::containing_address(section.end_address() as usize - 1);
-                let start_frame = Frame::containing_address(section.start_address() as usize - KERNEL_BASE);
-                let end_frame = Frame::containing_address(section.end_address() as usize - KERNEL_BASE - 1);
+                let start_page = Page::containing_address(section.start_address());
+                let end_page = Page::containing_address(section.end_address() - 1);
+                let start_frame = Frame::containing_address(section.start_address() - KERNEL_BASE);
+                let end_frame = Frame::containing_address(section.end_address() - KERNEL_BASE - 1);
                 for (page, frame) in Page::range_inclusive(start_page, end_page).zip(Frame::range_inclusive(start_frame, end_frame)) {
                     mapper.map_to(page, frame, flags, allocator);
                 }
             } else {
                 vgaprintln!("Identity mapping ELF section from {:#x} - {:#x}", section.start_address(), section.end_address());
-                let start_frame = Frame::containing_address(section.start_address() as usize);
-                let end_frame = Frame::containing_address(section.end_address() as usize - 1);
+                let start_frame = Frame::containing_address(section.start_address());
+                let end_frame = Frame::containing_address(section.end_address() - 1);
                 for frame in Frame::range_inclusive(start_frame, end_frame) {
                     mapper.identity_map(frame, flags, allocator);
                 }
@@ -122,21 +225,30 @@ pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Active
         // TODO : reset stack pointer, identity map p4 table(?)
         //mapper.identity_map(Frame::containing_address(mapper.p4() as *const _ as usize), EntryFlags::WRITABLE, allocator);
 
-        let mb_start = Frame::containing_address(boot_info.start_address() as usize);
-        let mb_end = Frame::containing_address(boot_info.end_address() as usize - 1);
-        for frame in Frame::range_inclusive(mb_start, mb_end) {
-            mapper.identity_map(frame, EntryFlags::PRESENT, allocator);
-        }
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, EntryFlags::WRITABLE, allocator);
+        // VGA output has to show up right away, even if the kernel hangs right after a write
+        let vga_buffer_page = Page::containing_address(KERNEL_VGA_BUFFER);
+        let vga_buffer_frame = Frame::containing_address(KERNEL_VGA_BUFFER - KERNEL_BASE);
+        let vga_flags = EntryFlags::WRITABLE | EntryFlags::NOEXEC | MemoryType::Uncacheable.flags();
+        mapper.map_to(vga_buffer_page, vga_buffer_frame, vga_flags, allocator);
     });
-    let old_table = active_table.switch(new_table);
+    active_table.switch(new_table);
 
-    // Reuse the old p2 and p3 tables for stack, with the old p4 table becoming a stack guard
-    // we can use the frame address because it's identity mapped
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
-    vgaprintln!("Stack guard page at {:#x}", old_p4_page.start_address());
+    // TODO(arch) abstract away x86_64 symbols
+    extern "C" {
+        static p4_table: u8;
+    }
+    // The boot stack is the 4 pages after the old p4, p3 and p2 tables (see boot.S), so the old p2
+    // table, which sits right below it, becomes its guard page.
+    // The old p4 table isn't always the old table's root (with LA57, the p5 table is), so it is
+    // found through its symbol instead. We can use its address because it's identity mapped.
+    let old_p4_address = unsafe { &p4_table as *const _ as usize };
+    let guard_page = Page::containing_address(old_p4_address + 2 * PAGE_SIZE);
+    active_table.unmap(guard_page, allocator);
+    vgaprintln!("Stack guard page at {:#x}", guard_page.start_address());
+
+    let stack_bottom = old_p4_address + 3 * PAGE_SIZE;
+    let stack_top = old_p4_address + 7 * PAGE_SIZE;
+    register_guard_page(GuardPage::new(guard_page.start_address(), stack_bottom, stack_top, "boot stack"));
 
     active_table
 }
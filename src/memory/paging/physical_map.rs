@@ -0,0 +1,158 @@
+//! A direct map of physical memory into the kernel's half of the address space.
+//!
+//! Once the physical map is set up, any frame can be read or written at a fixed offset, and page
+//! tables are walked through it instead of through the recursive P4 entry. This also means that
+//! inactive page tables can be edited directly, without having to go through a `TemporaryPage`.
+
+use core::cmp::Ordering;
+use spin::Once;
+use boot_info::{BootInfo, MAX_MEMORY_AREAS};
+use memory::{Frame, FrameAllocator, PAGE_SIZE, map::{PHYSICAL_MAP_START, PHYSICAL_MAP_SIZE}};
+use memory::paging::{
+    ActivePageTable, EntryFlags, Page, PageSize, Size2MiB, PhysicalAddress, VirtualAddress,
+};
+
+/// The physical ranges that are mapped, which is set once the physical map is set up.
+static MAPPED_RANGES: Once<MappedRanges> = Once::new();
+
+/// The page-aligned physical ranges that the physical map covers, sorted and without overlaps.
+///
+/// Each usable memory area adds one range, so there is always room for all of them.
+struct MappedRanges {
+    ranges: [(PhysicalAddress, PhysicalAddress); MAX_MEMORY_AREAS],
+    count: usize,
+}
+
+impl MappedRanges {
+    /// Gets the mapped ranges, as start and end (exclusive) addresses.
+    fn ranges(&self) -> &[(PhysicalAddress, PhysicalAddress)] {
+        &self.ranges[.. self.count]
+    }
+
+    /// Gets whether the given physical address is mapped.
+    fn contains(&self, address: PhysicalAddress) -> bool {
+        self.ranges()
+            .binary_search_by(|&(start, end)| {
+                if end <= address {
+                    Ordering::Less
+                } else if start > address {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    /// Adds a range. `sort` must be called once every range has been added.
+    fn push(&mut self, start: PhysicalAddress, end: PhysicalAddress) {
+        self.ranges[self.count] = (start, end);
+        self.count += 1;
+    }
+
+    /// Sorts the ranges, merging the ones that touch or overlap.
+    fn sort(&mut self) {
+        self.ranges[.. self.count].sort_unstable();
+        let mut merged = 0;
+        for i in 0 .. self.count {
+            let (start, end) = self.ranges[i];
+            if merged > 0 && start <= self.ranges[merged - 1].1 {
+                let last = &mut self.ranges[merged - 1];
+                last.1 = last.1.max(end);
+            } else {
+                self.ranges[merged] = (start, end);
+                merged += 1;
+            }
+        }
+        self.count = merged;
+    }
+}
+
+/// Gets whether the physical map has been set up.
+pub fn physical_map_enabled() -> bool {
+    MAPPED_RANGES.try().is_some()
+}
+
+/// Gets the virtual address that the given physical address can be accessed at through the
+/// physical map.
+///
+/// `None` is returned if the physical map isn't set up, or if the address isn't in a frame that
+/// overlaps usable memory, since only those are mapped.
+pub fn phys_to_virt(address: PhysicalAddress) -> Option<VirtualAddress> {
+    if MAPPED_RANGES.try()?.contains(address) {
+        Some(PHYSICAL_MAP_START + address)
+    } else {
+        None
+    }
+}
+
+/// Gets the physical address of a virtual address that lies inside of the physical map.
+pub fn virt_to_phys(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if address < PHYSICAL_MAP_START {
+        return None;
+    }
+    let physical_address = address - PHYSICAL_MAP_START;
+    phys_to_virt(physical_address).map(|_| physical_address)
+}
+
+impl Frame {
+    /// Gets the address that this frame can be accessed at through the physical map, if it is set
+    /// up.
+    pub fn virtual_address(&self) -> Option<VirtualAddress> {
+        phys_to_virt(self.start_address())
+    }
+}
+
+/// Maps every usable area of physical memory at `PHYSICAL_MAP_START`.
+///
+/// 2 MiB pages are used where an area covers a whole chunk, and 4 KiB pages at its edges, so that
+/// memory outside of the usable areas (like device memory) is never mapped as write-back.
+///
+/// This must be called after the kernel is remapped, since the page tables that are used to build
+/// the physical map have to be reachable from the kernel's own P4 table.
+pub (in memory) fn init_physical_map<A>(boot_info: &BootInfo, active_table: &mut ActivePageTable,
+                                        allocator: &mut A)
+    where A: FrameAllocator
+{
+    let flags = EntryFlags::WRITABLE | EntryFlags::NOEXEC;
+    let mut mapped = MappedRanges {
+        ranges: [(0, 0); MAX_MEMORY_AREAS],
+        count: 0,
+    };
+    for area in boot_info.usable_memory_areas() {
+        let area_start = area.start / PAGE_SIZE * PAGE_SIZE;
+        let area_end = (area.end() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if area_end <= area_start {
+            continue;
+        }
+        assert!(area_end <= PHYSICAL_MAP_SIZE,
+                "Memory area at {:#x} - {:#x} does not fit in the physical map", area.start, area_end);
+
+        let mut address = area_start;
+        while address < area_end {
+            let page = Page::containing_address(PHYSICAL_MAP_START + address);
+            // neighbouring areas may share a frame
+            let already_mapped = active_table.translate_page(page).is_some();
+            let size = if !already_mapped && address % Size2MiB::SIZE == 0 && area_end - address >= Size2MiB::SIZE {
+                Size2MiB::SIZE
+            } else {
+                PAGE_SIZE
+            };
+            if !already_mapped {
+                let frame = Frame::containing_address(address);
+                if size == PAGE_SIZE {
+                    active_table.map_to(page, frame, flags, allocator);
+                } else {
+                    let page = Page::<Size2MiB>::from_start_address(page.start_address());
+                    active_table.map_to_huge(page, frame, flags, allocator);
+                }
+            }
+            address += size;
+        }
+        mapped.push(area_start, area_end);
+    }
+    mapped.sort();
+    let end = mapped.ranges().last().map(|&(_, end)| end).unwrap_or(0);
+    vgaprintln!("Physical memory mapped at {:#x} - {:#x}", PHYSICAL_MAP_START, PHYSICAL_MAP_START + end);
+    MAPPED_RANGES.call_once(|| mapped);
+}
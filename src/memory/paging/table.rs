@@ -2,21 +2,26 @@ use core::ops::{Index, IndexMut, Deref, DerefMut};
 use core::marker::PhantomData;
 use memory::frame::{Frame, FrameAllocator};
 use memory::paging::{
-    Entry, EntryFlags, Mapper,
+    EditGuard, Entry, EntryFlags, Mapper, MapError, Page,
     temporary_page::TemporaryPage,
-    VirtualAddress,
+    VirtualAddress, physical_map_enabled, tlb,
 };
+// TODO(arch) abstract away x86_64 calls
+use arch::x86_64::{la57_enabled, pcid_enabled};
 
 /// The number of page entries per page table.
 pub (in memory) const ENTRY_COUNT: usize = 512;
 
 pub trait TableLevel {}
 
+/// The root table of five-level paging, which is only ever reached through the physical map.
+pub enum TableLevel5 {}
 pub enum TableLevel4 {}
 pub enum TableLevel3 {}
 pub enum TableLevel2 {}
 pub enum TableLevel1 {}
 
+impl TableLevel for TableLevel5 {}
 impl TableLevel for TableLevel4 {}
 impl TableLevel for TableLevel3 {}
 impl TableLevel for TableLevel2 {}
@@ -26,6 +31,10 @@ pub trait TableLevelHeirarchy: TableLevel {
     type NextLevel: TableLevel;
 }
 
+impl TableLevelHeirarchy for TableLevel5 {
+    type NextLevel = TableLevel4;
+}
+
 impl TableLevelHeirarchy for TableLevel4 {
     type NextLevel = TableLevel3;
 }
@@ -54,6 +63,12 @@ impl<L> Table<L>
         self.entries.iter_mut()
             .for_each(Entry::set_unused);
     }
+
+    /// Gets whether none of the entries in this page table are used.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter()
+            .all(|entry| !entry.is_used())
+    }
 }
 
 impl<L> Table<L>
@@ -61,11 +76,17 @@ impl<L> Table<L>
 {
     /// Gets the address of the next page table down the line.
     ///
+    /// If the physical map is set up, the table is reached through it; otherwise, this table must
+    /// have been reached through the recursive mapping.
+    ///
     /// If we are at the last page table, or if the next page table at the given index doesn't
     /// exist, `None` is returned.
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let flags = self.entries[index].flags();
         if flags.contains(EntryFlags::PRESENT) && !flags.contains(EntryFlags::HUGE) {
+            if let Some(address) = self.entries[index].to_frame().and_then(|frame| frame.virtual_address()) {
+                return Some(address);
+            }
             let table_address = self as *const _ as usize;
             Some((table_address << 9) | (index << 12))
         } else {
@@ -91,16 +112,83 @@ impl<L> Table<L>
             .map(|addr| unsafe { &mut *(addr as *mut _)})
     }
 
+    /// Gets a reference to the next page table down the line, or the reason that it can't be
+    /// reached.
+    pub fn try_next_table(&self, index: usize) -> Result<&Table<L::NextLevel>, MapError> {
+        self.check_next_table(index)?;
+        Ok(self.next_table(index).unwrap())
+    }
+
+    /// Gets a mutable reference to the next page table down the line, or the reason that it can't
+    /// be reached.
+    pub fn try_next_table_mut(&mut self, index: usize) -> Result<&mut Table<L::NextLevel>, MapError> {
+        self.check_next_table(index)?;
+        Ok(self.next_table_mut(index).unwrap())
+    }
+
+    /// Checks that the entry at the given index points to a page table.
+    fn check_next_table(&self, index: usize) -> Result<(), MapError> {
+        let flags = self.entries[index].flags();
+        if !flags.contains(EntryFlags::PRESENT) {
+            Err(MapError::NotMapped)
+        } else if flags.contains(EntryFlags::HUGE) {
+            Err(MapError::HugePageInTheWay)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Gets a mutable reference to the next page table down the line, creating it if it doesn't
+    /// exist yet.
     pub fn next_table_create<A>(&mut self, index: usize, alloc: &mut A) -> &mut Table<L::NextLevel>
         where A: FrameAllocator
+    {
+        match self.next_table_try_create(index, alloc) {
+            Ok(table) => table,
+            Err(MapError::HugePageInTheWay) =>
+                panic!("Attempted to create a page table where a huge page is already mapped"),
+            Err(error) => panic!("Could not create page table: {}", error),
+        }
+    }
+
+    /// Gets a mutable reference to the next page table down the line, creating it if it doesn't
+    /// exist yet, or returns an error if it can't be created.
+    pub fn next_table_try_create<A>(&mut self, index: usize, alloc: &mut A)
+        -> Result<&mut Table<L::NextLevel>, MapError>
+        where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(EntryFlags::HUGE), "Hugepages are not allowed yet");
-            let frame = alloc.alloc().expect("No available frames");
+            if self.entries[index].flags().contains(EntryFlags::HUGE) {
+                return Err(MapError::HugePageInTheWay);
+            }
+            let frame = alloc.alloc()
+                .ok_or(MapError::OutOfFrames)?;
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
+    }
+}
+
+impl<L> Table<L>
+    where L: TableLevelHeirarchy
+{
+    /// Gives back the next page table at the given index if none of its entries are used.
+    ///
+    /// Returns whether the table was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return false,
+        };
+        let frame = self.entries[index].to_frame().unwrap();
+        self.entries[index].set_unused();
+        // the table is no longer reachable through the recursive mapping
+        tlb::flush(table_address);
+        allocator.dealloc(frame);
+        true
     }
 }
 
@@ -141,20 +229,35 @@ impl DerefMut for ActivePageTable {
 }
 
 impl ActivePageTable {
+    /// Creates a handle to the active page table.
+    ///
+    /// This is unsafe because every handle edits the same tables. A second handle may only be
+    /// used while no other handle is in the middle of an edit, which `page_table_edit_in_progress`
+    /// tells, e.g. from a page fault that interrupted whoever holds the first one.
     pub (in memory) unsafe fn new() -> Self {
         ActivePageTable {
             mapper: Mapper::new(),
         }
     }
 
+    /// Calls `f` with a mapper for an inactive page table.
+    ///
+    /// If the physical map is set up, the inactive table is edited directly and `temporary_page`
+    /// goes unused. Otherwise, the recursive mapping is pointed at the inactive table for the
+    /// duration of the call.
     pub fn with<F>(&mut self, table: &mut InactivePageTable, temporary_page: &mut TemporaryPage, f: F)
         where F: FnOnce(&mut Mapper)
     {
-        use x86_64::instructions::tlb;
-        use x86_64::registers::control_regs;
+        let _edit = EditGuard::new();
+        if physical_map_enabled() {
+            return table.with(f);
+        }
 
+        // the recursive mapping isn't global, so reloading CR3 is enough to drop it; this only
+        // happens while remapping the kernel, before the physical map exists
         {
-            let old_p4 = Frame::containing_address( control_regs::cr3().0 as usize );
+            // CR3 may point to a P5 table, so the P4 table is found through the recursive entry
+            let old_p4 = self.current_table().p4_frame;
 
             let p4_table = temporary_page.map_to_table(old_p4.clone(), self);
 
@@ -177,43 +280,81 @@ impl ActivePageTable {
         use x86_64::PhysicalAddress;
         use x86_64::registers::control_regs;;
 
-        let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(control_regs::cr3().0 as usize),
-        };
+        let old_table = self.current_table();
         unsafe {
-            control_regs::cr3_write(PhysicalAddress(new_table.p4_frame.start_address() as u64));
+            control_regs::cr3_write(PhysicalAddress(new_table.root_frame().start_address() as u64));
         }
         old_table
     }
 
-    pub unsafe fn address_to_entry(&self, address: VirtualAddress) -> &Entry {
-        let p4_index = (address >> 39) & 0o777;
-        let p3_index = (address >> 30) & 0o777;
-        let p2_index = (address >> 21) & 0o777;
-        let p1_index = (address >> 12) & 0o777;
-        let p1 = self.p4()
-            .next_table(p4_index).unwrap()
-            .next_table(p3_index).unwrap()
-            .next_table(p2_index).unwrap();
-        &p1[p1_index]
+    /// Switches to a page table whose TLB entries are tagged with the given PCID.
+    ///
+    /// Unlike `switch`, the TLB isn't flushed, so entries that are already tagged with `pcid` must
+    /// still be valid for `new_table`. Kernel pages are global, so they are shared by every PCID.
+    ///
+    /// # Arguments
+    /// `new_table` - the page table to switch to.
+    /// `pcid` - the PCID that `new_table` was handed by `tlb::alloc_pcid`.
+    pub fn switch_tagged(&mut self, new_table: InactivePageTable, pcid: u16) -> InactivePageTable {
+        use x86_64::PhysicalAddress;
+        use x86_64::registers::control_regs;
+
+        /// CR3 bit 63, which keeps the TLB entries of the new PCID.
+        const CR3_NO_FLUSH: u64 = 1 << 63;
+
+        assert!(pcid_enabled(), "Attempted to switch to PCID {} while PCIDs are disabled", pcid);
+        let old_table = self.current_table();
+        let cr3 = new_table.root_frame().start_address() as u64 | pcid as u64 | CR3_NO_FLUSH;
+        unsafe {
+            control_regs::cr3_write(PhysicalAddress(cr3));
+        }
+        old_table
     }
 
-    pub unsafe fn address_to_entry_mut(&mut self, address: VirtualAddress) -> &mut Entry {
-        let p4_index = (address >> 39) & 0o777;
-        let p3_index = (address >> 30) & 0o777;
-        let p2_index = (address >> 21) & 0o777;
-        let p1_index = (address >> 12) & 0o777;
-        let p1 = self.p4_mut()
-            .next_table_mut(p4_index).unwrap()
-            .next_table_mut(p3_index).unwrap()
-            .next_table_mut(p2_index).unwrap();
-        &mut p1[p1_index]
+    /// Gets the page table that is currently active, to hand back when switching away from it.
+    fn current_table(&self) -> InactivePageTable {
+        use x86_64::registers::control_regs;
+
+        // with five-level paging, CR3 points to the P5 table, but the recursive entry still
+        // points to the P4 table that it is folded onto
+        let root_frame = Frame::containing_address(control_regs::cr3().0 as usize);
+        InactivePageTable {
+            p4_frame: self.p4()[511].to_frame().unwrap(),
+            p5_frame: if la57_enabled() { Some(root_frame) } else { None },
+        }
+    }
+
+    /// Gets the P1 entry that maps the given address.
+    pub unsafe fn address_to_entry(&self, address: VirtualAddress) -> Result<&Entry, MapError> {
+        let page = Page::containing_address(address);
+        let p1 = self.p4_for(page.p5_index())?
+            .try_next_table(page.p4_index())?
+            .try_next_table(page.p3_index())?
+            .try_next_table(page.p2_index())?;
+        Ok(&p1[page.p1_index()])
+    }
+
+    /// Gets the P1 entry that maps the given address, mutably.
+    pub unsafe fn address_to_entry_mut(&mut self, address: VirtualAddress) -> Result<&mut Entry, MapError> {
+        let page = Page::containing_address(address);
+        let p1 = self.p4_for_mut(page.p5_index())?
+            .try_next_table_mut(page.p4_index())?
+            .try_next_table_mut(page.p3_index())?
+            .try_next_table_mut(page.p2_index())?;
+        Ok(&mut p1[page.p1_index()])
     }
 }
 
 /// An page table that is not currently active.
 pub struct InactivePageTable {
     pub(in memory) p4_frame: Frame,
+
+    /// The P5 table that is folded onto the P4 table, if five-level paging is enabled.
+    ///
+    /// Entries 0 and 511 of the P5 table point to the P4 table, so the lower and upper 128 TiB of
+    /// the address space are laid out the same as with four-level paging. The rest of the P5
+    /// table maps the extra address space that five-level paging brings.
+    pub(in memory) p5_frame: Option<Frame>,
 }
 
 impl InactivePageTable {
@@ -224,7 +365,15 @@ impl InactivePageTable {
     /// `active_table` - the active page table used to access the inactive page table.
     /// `temporary_page` - the temporary page that is used to store the inactive page table while
     ///                    we write to it.
-    pub fn new(p4_frame: Frame, active_table: &mut ActivePageTable, temporary_page: &mut TemporaryPage) -> Self {
+    /// `allocator` - the allocator to get the P5 table from, if five-level paging is enabled.
+    pub fn new<A>(p4_frame: Frame, active_table: &mut ActivePageTable, temporary_page: &mut TemporaryPage,
+                  allocator: &mut A) -> Self
+        where A: FrameAllocator
+    {
+        if physical_map_enabled() {
+            return InactivePageTable::new_direct(p4_frame, allocator)
+                .unwrap_or_else(|error| panic!("Could not create page table: {}", error));
+        }
         {
             // create the new table from the temporary page
             let table = temporary_page.map_to_table(p4_frame.clone(), active_table);
@@ -235,8 +384,101 @@ impl InactivePageTable {
         }
         temporary_page.unmap(active_table);
 
-        InactivePageTable { p4_frame, }
+        let p5_frame = if la57_enabled() {
+            let p5_frame = allocator.alloc().expect("No frames available for the P5 table");
+            fold_p5(temporary_page.map_to_table(p5_frame.clone(), active_table), &p4_frame);
+            temporary_page.unmap(active_table);
+            Some(p5_frame)
+        } else {
+            None
+        };
+
+        InactivePageTable { p4_frame, p5_frame, }
     }
+
+    /// Creates a new inactive page table, writing to it through the physical map.
+    ///
+    /// If five-level paging is enabled and the P5 table can't be allocated, `p4_frame` is given
+    /// back to the allocator.
+    ///
+    /// # Arguments
+    /// `p4_frame` - the allocated frame to use for this new page table.
+    /// `allocator` - the allocator to get the P5 table from, if five-level paging is enabled.
+    pub fn new_direct<A>(p4_frame: Frame, allocator: &mut A) -> Result<Self, MapError>
+        where A: FrameAllocator
+    {
+        let p5_frame = if la57_enabled() {
+            match allocator.alloc() {
+                Some(p5_frame) => Some(p5_frame),
+                None => {
+                    allocator.dealloc(p4_frame);
+                    return Err(MapError::OutOfFrames);
+                },
+            }
+        } else {
+            None
+        };
+
+        {
+            // see the note in TemporaryPage::map_to_table; this is actually a P4 table
+            let table = direct_table(&p4_frame);
+            table.zero();
+
+            // recursively map this table's frame
+            table[511].set(p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        }
+        if let Some(ref p5_frame) = p5_frame {
+            fold_p5(direct_table(p5_frame), &p4_frame);
+        }
+        Ok(InactivePageTable { p4_frame, p5_frame, })
+    }
+
+    /// Gets the frame that CR3 points to when this table is active.
+    pub (in memory) fn root_frame(&self) -> &Frame {
+        self.p5_frame.as_ref().unwrap_or(&self.p4_frame)
+    }
+
+    /// Gives back the P5 table of this page table, if it has one.
+    ///
+    /// The P4 table and everything below it are left alone.
+    pub (in memory) fn dealloc_p5<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        if let Some(p5_frame) = self.p5_frame.take() {
+            allocator.dealloc(p5_frame);
+        }
+    }
+
+    /// Calls `f` with a mapper that edits this page table directly through the physical map.
+    ///
+    /// Mappings that are changed this way are not flushed from any TLB, since this table isn't
+    /// active.
+    pub fn with<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Mapper) -> R
+    {
+        let address = self.p4_frame.virtual_address()
+            .expect("Inactive page tables can only be edited directly through the physical map");
+        let p5 = self.p5_frame.as_ref()
+            .map(|frame| frame.virtual_address().unwrap() as *mut Table<TableLevel5>);
+        let mut mapper = unsafe { Mapper::from_tables(address as *mut Table<TableLevel4>, p5) };
+        f(&mut mapper)
+    }
+}
+
+/// Gets a page table through the physical map, as a P1 table so that no next tables are walked
+/// through the recursive mapping by accident.
+fn direct_table(frame: &Frame) -> &'static mut Table<TableLevel1> {
+    let address = frame.virtual_address()
+        .expect("Page tables can only be created directly through the physical map");
+    unsafe { &mut *(address as *mut Table<TableLevel1>) }
+}
+
+/// Fills in a P5 table so that it is folded onto the given P4 table.
+fn fold_p5(p5: &mut Table<TableLevel1>, p4_frame: &Frame) {
+    p5.zero();
+    // user access is still decided by the P4 table's entries
+    p5[0].set(p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::USER);
+    p5[511].set(p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
 }
 
 /// Level 4 (top) page table.
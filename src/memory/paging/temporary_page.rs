@@ -38,7 +38,7 @@ impl TemporaryPage {
 
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddress {
         assert!(active_table.translate_page(self.page).is_none(), "Temporary page is already mapped");
-        active_table.map_to(self.page, frame, EntryFlags::WRITABLE, &mut self.alloc);
+        active_table.map_to(self.page, frame, EntryFlags::WRITABLE | EntryFlags::NOEXEC, &mut self.alloc);
         self.page.start_address()
     }
 
@@ -0,0 +1,178 @@
+//! TLB invalidation, and process-context identifiers (PCIDs) for tagging TLB entries with the
+//! address space that they belong to.
+//!
+//! Kernel pages are mapped global when the CPU supports it, so they are only flushed by `flush`,
+//! `flush_range` and `flush_everything`.
+//!
+//! TODO(arch) this is x86_64 specific
+
+use spin::Mutex;
+use memory::{PAGE_SIZE, VirtualAddress, map::KERNEL_BASE};
+use arch::x86_64::{CR4_PGE, global_pages_enabled, pcid_enabled, read_cr4, write_cr4};
+
+/// Ranges and batches with more pages than this are flushed all at once, rather than page by
+/// page.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// The number of PCIDs that the CPU supports. PCID 0 is never handed out; it is used by the
+/// kernel's own page table.
+const PCID_COUNT: usize = 4096;
+
+/// The `invpcid` type that invalidates every entry of a single PCID, except for global ones.
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+
+/// The `invpcid` type that invalidates every entry of every PCID, including global ones.
+const INVPCID_ALL_CONTEXTS_GLOBAL: u64 = 2;
+
+/// The PCIDs that are handed out, one bit each.
+static PCIDS: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new([0; PCID_COUNT / 64]);
+
+/// Flushes the TLB entry for the page that contains the given address.
+pub fn flush(address: VirtualAddress) {
+    use x86_64::instructions::tlb;
+    use x86_64::VirtualAddress;
+
+    tlb::flush(VirtualAddress(address));
+}
+
+/// Flushes every TLB entry for the given range of addresses.
+///
+/// Large ranges are flushed all at once instead of page by page.
+///
+/// # Arguments
+/// `start` - the address to start at.
+/// `end` - the address to stop at (exclusive).
+pub fn flush_range(start: VirtualAddress, end: VirtualAddress) {
+    let page_count = (end - start + PAGE_SIZE - 1) / PAGE_SIZE;
+    if page_count > FLUSH_ALL_THRESHOLD {
+        flush_all_for(start, end);
+    } else {
+        for page in 0 .. page_count {
+            flush(start + page * PAGE_SIZE);
+        }
+    }
+}
+
+/// Flushes every non-global TLB entry of the active address space.
+pub fn flush_all() {
+    use x86_64::instructions::tlb;
+
+    tlb::flush_all();
+}
+
+/// Flushes every TLB entry, including global ones and ones that are tagged with other PCIDs.
+pub fn flush_everything() {
+    if pcid_enabled() {
+        unsafe { invpcid(INVPCID_ALL_CONTEXTS_GLOBAL, 0, 0); }
+    } else if global_pages_enabled() {
+        // toggling global pages off and back on drops every global entry
+        let cr4 = read_cr4();
+        unsafe {
+            write_cr4(cr4 & !CR4_PGE);
+            write_cr4(cr4);
+        }
+    } else {
+        flush_all();
+    }
+}
+
+/// Flushes everything that a full flush of the given range needs to.
+fn flush_all_for(start: VirtualAddress, end: VirtualAddress) {
+    // kernel pages are global, so reloading CR3 doesn't drop them
+    if end > KERNEL_BASE || start >= KERNEL_BASE {
+        flush_everything();
+    } else {
+        flush_all();
+    }
+}
+
+/// Collects pages whose mappings changed, so that they can be flushed together.
+///
+/// Whatever is left in the batch is flushed when it is dropped.
+pub struct TlbBatch {
+    pages: [VirtualAddress; FLUSH_ALL_THRESHOLD],
+    count: usize,
+
+    /// The lowest and highest page that were added, for when there are too many pages to flush
+    /// one by one.
+    lowest: VirtualAddress,
+    highest: VirtualAddress,
+}
+
+impl TlbBatch {
+    pub fn new() -> Self {
+        TlbBatch {
+            pages: [0; FLUSH_ALL_THRESHOLD],
+            count: 0,
+            lowest: !0,
+            highest: 0,
+        }
+    }
+
+    /// Adds the page that contains the given address to the batch.
+    pub fn add(&mut self, address: VirtualAddress) {
+        if self.count < FLUSH_ALL_THRESHOLD {
+            self.pages[self.count] = address;
+        }
+        self.count += 1;
+        self.lowest = self.lowest.min(address);
+        self.highest = self.highest.max(address);
+    }
+
+    /// Flushes every page in the batch, and empties it.
+    pub fn flush(&mut self) {
+        if self.count > FLUSH_ALL_THRESHOLD {
+            flush_all_for(self.lowest, self.highest + PAGE_SIZE);
+        } else {
+            for &address in &self.pages[.. self.count] {
+                flush(address);
+            }
+        }
+        *self = TlbBatch::new();
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Hands out a PCID for an address space to tag its TLB entries with.
+///
+/// `None` is returned if PCIDs aren't enabled or if they have all been handed out, in which case
+/// the address space has to do without.
+pub fn alloc_pcid() -> Option<u16> {
+    if !pcid_enabled() {
+        return None;
+    }
+    let mut pcids = PCIDS.lock();
+    let pcid = (1 .. PCID_COUNT)
+        .find(|&pcid| pcids[pcid / 64] & (1 << (pcid % 64)) == 0)?;
+    pcids[pcid / 64] |= 1 << (pcid % 64);
+    Some(pcid as u16)
+}
+
+/// Gives back a PCID that was handed out by `alloc_pcid`, flushing every TLB entry that is tagged
+/// with it.
+pub fn free_pcid(pcid: u16) {
+    invalidate_pcid(pcid);
+    let pcid = pcid as usize;
+    let mut pcids = PCIDS.lock();
+    assert!(pcids[pcid / 64] & (1 << (pcid % 64)) != 0, "Attempted to free PCID {} twice", pcid);
+    pcids[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// Flushes every non-global TLB entry that is tagged with the given PCID.
+///
+/// This must be done whenever an address space that isn't active is changed, since its stale
+/// entries would otherwise be used the next time it is switched to.
+pub fn invalidate_pcid(pcid: u16) {
+    unsafe { invpcid(INVPCID_SINGLE_CONTEXT, pcid, 0); }
+}
+
+/// Runs the `invpcid` instruction.
+unsafe fn invpcid(kind: u64, pcid: u16, address: VirtualAddress) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    asm!("invpcid ($0), $1" :: "r"(&descriptor), "r"(kind) : "memory");
+}
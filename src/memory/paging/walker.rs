@@ -0,0 +1,253 @@
+//! Walks the page tables of an address space, so its mappings can be inspected when debugging.
+
+use core::{fmt, mem};
+use memory::paging::{
+    Mapper, Entry, EntryFlags, Table, TableLevel4, PageSize, Size4KiB, Size2MiB, Size1GiB, PhysicalAddress,
+    VirtualAddress,
+};
+
+/// The size of the region that a single P4 entry maps.
+const P4_ENTRY_SIZE: usize = Size1GiB::SIZE * 512;
+
+/// The end of the address space, before sign extension.
+const ADDRESS_SPACE_END: usize = P4_ENTRY_SIZE * 512;
+
+/// A virtual range that is mapped to a physical range, using pages of a single size that all
+/// have the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    /// The virtual address that this mapping starts at.
+    pub start: VirtualAddress,
+
+    /// The virtual address that this mapping ends at (exclusive).
+    pub end: VirtualAddress,
+
+    /// The physical address that `start` is mapped to.
+    pub physical_start: PhysicalAddress,
+
+    /// The size of the pages that make up this mapping.
+    pub page_size: usize,
+
+    /// The effective flags of this mapping, taking the page tables above it into account.
+    ///
+    /// `ACCESSED`, `DIRTY` and `HUGE` are never included, though any page may include `PAT`,
+    /// which is the same bit as `HUGE`. Huge pages report `PAT` the way a P1 entry would have it.
+    pub flags: EntryFlags,
+}
+
+impl Mapping {
+    /// Gets the size of this mapping, in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Gets the physical address that this mapping ends at (exclusive).
+    pub fn physical_end(&self) -> PhysicalAddress {
+        self.physical_start + self.size()
+    }
+
+    /// Extends this mapping with `other` if it directly follows this one, both virtually and
+    /// physically, and has the same page size and flags.
+    ///
+    /// Returns whether the mappings were merged.
+    fn merge(&mut self, other: &Mapping) -> bool {
+        if self.end == other.start && self.physical_end() == other.physical_start
+            && self.page_size == other.page_size && self.flags == other.flags
+        {
+            self.end = other.end;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag: EntryFlags, set: &'static str, unset: &'static str| {
+            if self.flags.contains(flag) { set } else { unset }
+        };
+        let (size, unit) = human_size(self.size());
+        let (page_size, page_unit) = human_size(self.page_size);
+        write!(f, "{:#018x}-{:#018x} {:>4}{} -> {:#014x} {} {} {} {} {}{}",
+               self.start, self.end, size, unit, self.physical_start,
+               flag(EntryFlags::USER, "usr", "   "),
+               flag(EntryFlags::WRITABLE, "RW", "ro"),
+               flag(EntryFlags::NOEXEC, "NX", "x "),
+               flag(EntryFlags::GLOBAL, "GLB", "   "),
+               page_size, page_unit)
+    }
+}
+
+/// Splits a size up into the largest unit that it is a multiple of.
+fn human_size(size: usize) -> (usize, &'static str) {
+    if size % Size1GiB::SIZE == 0 {
+        (size / Size1GiB::SIZE, "G")
+    } else if size % 0x10_0000 == 0 {
+        (size / 0x10_0000, "M")
+    } else {
+        (size / 0x400, "K")
+    }
+}
+
+/// Sign-extends an address, so it is canonical.
+fn canonical(address: usize) -> VirtualAddress {
+    if address & (1 << 47) != 0 {
+        address | 0xffff_0000_0000_0000
+    } else {
+        address
+    }
+}
+
+/// An iterator over every present mapping of a page table, in order of virtual address.
+///
+/// Adjacent pages are merged into a single `Mapping` where possible. The recursive mapping in
+/// P4 entry 511 is skipped. With five-level paging, only the lower and upper 128 TiB that the P5
+/// table is folded onto are walked.
+pub struct Mappings<'a> {
+    p4: &'a Table<TableLevel4>,
+
+    /// The next address to look up, before sign extension.
+    next: usize,
+
+    /// The mapping that is currently being merged with the ones that follow it.
+    pending: Option<Mapping>,
+}
+
+impl<'a> Mappings<'a> {
+    /// Finds the next page that is mapped.
+    fn next_page(&mut self) -> Option<Mapping> {
+        while self.next < ADDRESS_SPACE_END {
+            let (size, mapping) = self.lookup(self.next);
+            self.next += size;
+            if mapping.is_some() {
+                return mapping;
+            }
+        }
+        None
+    }
+
+    /// Looks up whatever maps `address`, which is aligned to the size of the region that it was
+    /// reached with.
+    ///
+    /// Returns the size of the region that was looked at, along with the mapping for it if there
+    /// is one.
+    fn lookup(&self, address: usize) -> (usize, Option<Mapping>) {
+        let p4_index = (address >> 39) & 0o777;
+        let p3_index = (address >> 30) & 0o777;
+        let p2_index = (address >> 21) & 0o777;
+        let p1_index = (address >> 12) & 0o777;
+
+        // the recursive mapping would show every page table as a mapping
+        if p4_index == 511 {
+            return (P4_ENTRY_SIZE, None);
+        }
+        let p4_entry = &self.p4[p4_index];
+        let p3 = match self.p4.next_table(p4_index) {
+            Some(p3) => p3,
+            None => return (P4_ENTRY_SIZE, None),
+        };
+
+        let p3_entry = &p3[p3_index];
+        if is_huge(p3_entry) {
+            return (Size1GiB::SIZE, Some(mapping(address, Size1GiB::SIZE, p3_entry, &[p4_entry])));
+        }
+        let p2 = match p3.next_table(p3_index) {
+            Some(p2) => p2,
+            None => return (Size1GiB::SIZE, None),
+        };
+
+        let p2_entry = &p2[p2_index];
+        if is_huge(p2_entry) {
+            return (Size2MiB::SIZE, Some(mapping(address, Size2MiB::SIZE, p2_entry, &[p4_entry, p3_entry])));
+        }
+        let p1 = match p2.next_table(p2_index) {
+            Some(p1) => p1,
+            None => return (Size2MiB::SIZE, None),
+        };
+
+        let p1_entry = &p1[p1_index];
+        if p1_entry.flags().contains(EntryFlags::PRESENT) {
+            (Size4KiB::SIZE, Some(mapping(address, Size4KiB::SIZE, p1_entry, &[p4_entry, p3_entry, p2_entry])))
+        } else {
+            (Size4KiB::SIZE, None)
+        }
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(page) = self.next_page() {
+            if let Some(ref mut pending) = self.pending {
+                if pending.merge(&page) {
+                    continue;
+                }
+            }
+            let previous = mem::replace(&mut self.pending, Some(page));
+            if previous.is_some() {
+                return previous;
+            }
+        }
+        self.pending.take()
+    }
+}
+
+/// Gets whether the entry maps a huge page.
+fn is_huge(entry: &Entry) -> bool {
+    entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE)
+}
+
+/// Creates the mapping for a single page.
+///
+/// # Arguments
+/// `address` - the address of the page, before sign extension.
+/// `size` - the size of the page.
+/// `entry` - the entry that maps the page.
+/// `parents` - the entries of the page tables above `entry`, which restrict its flags.
+fn mapping(address: usize, size: usize, entry: &Entry, parents: &[&Entry]) -> Mapping {
+    let flags = if size == Size4KiB::SIZE { entry.flags() } else { entry.huge_flags() };
+    let mut flags = flags - (EntryFlags::ACCESSED | EntryFlags::DIRTY);
+    for parent in parents {
+        let parent_flags = parent.flags();
+        if !parent_flags.contains(EntryFlags::WRITABLE) {
+            flags.remove(EntryFlags::WRITABLE);
+        }
+        if !parent_flags.contains(EntryFlags::USER) {
+            flags.remove(EntryFlags::USER);
+        }
+        if parent_flags.contains(EntryFlags::NOEXEC) {
+            flags.insert(EntryFlags::NOEXEC);
+        }
+    }
+    let frame = if size == Size4KiB::SIZE { entry.to_frame() } else { entry.to_huge_frame() };
+    let start = canonical(address);
+    Mapping {
+        start,
+        end: start + size,
+        physical_start: frame.unwrap().start_address(),
+        page_size: size,
+        flags,
+    }
+}
+
+impl Mapper {
+    /// Gets an iterator over every present mapping of this page table.
+    pub fn mappings(&self) -> Mappings {
+        Mappings {
+            p4: self.p4(),
+            next: 0,
+            pending: None,
+        }
+    }
+}
+
+/// Prints every present mapping of a page table, in the style of Linux's
+/// `/sys/kernel/debug/page_tables`.
+pub fn print_mappings(mapper: &Mapper) {
+    vgaprintln!("Page table mappings:");
+    for mapping in mapper.mappings() {
+        vgaprintln!("  {}", mapping);
+    }
+}
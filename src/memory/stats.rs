@@ -0,0 +1,128 @@
+use core::fmt;
+use memory::{PAGE_SIZE, PhysicalAddress};
+
+/// The type of a memory area, as reported by the firmware through multiboot2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAreaType {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+    Unknown(u32),
+}
+
+impl From<u32> for MemoryAreaType {
+    fn from(typ: u32) -> Self {
+        match typ {
+            1 => MemoryAreaType::Available,
+            2 => MemoryAreaType::Reserved,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::AcpiNvs,
+            5 => MemoryAreaType::Defective,
+            typ => MemoryAreaType::Unknown(typ),
+        }
+    }
+}
+
+impl fmt::Display for MemoryAreaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryAreaType::Available => write!(f, "available"),
+            MemoryAreaType::Reserved => write!(f, "reserved"),
+            MemoryAreaType::AcpiReclaimable => write!(f, "ACPI reclaimable"),
+            MemoryAreaType::AcpiNvs => write!(f, "ACPI NVS"),
+            MemoryAreaType::Defective => write!(f, "defective"),
+            MemoryAreaType::Unknown(typ) => write!(f, "unknown ({})", typ),
+        }
+    }
+}
+
+/// A memory area from the multiboot2 memory map.
+///
+/// Unlike `multiboot2::MemoryArea`, these are also given out for areas that aren't available.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapArea {
+    pub start: PhysicalAddress,
+    pub size: usize,
+    pub typ: MemoryAreaType,
+}
+
+impl MemoryMapArea {
+    pub fn end(&self) -> PhysicalAddress {
+        self.start + self.size
+    }
+}
+
+/// Prints every area of the memory map, in the style of the e820 table that BIOSes report.
+pub fn print_memory_map(areas: &[MemoryMapArea]) {
+    vgaprintln!("Memory map:");
+    for area in areas {
+        vgaprintln!("  {:#018x} - {:#018x} {}", area.start, area.end().saturating_sub(1), area.typ);
+    }
+}
+
+/// Physical memory statistics. All counts are in frames.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// All frames that are described by the memory map.
+    pub total: usize,
+    /// Frames in available memory areas.
+    pub usable: usize,
+    /// Frames in memory areas that are not available.
+    pub reserved: usize,
+    /// Frames that hold the kernel image.
+    pub kernel: usize,
+    /// Usable frames that are currently free.
+    pub free: usize,
+    /// Usable frames that are currently not free, including the kernel frames.
+    pub allocated: usize,
+}
+
+impl MemoryStats {
+    /// Gathers the statistics that don't change after boot from the memory map.
+    ///
+    /// `free` and `allocated` are left at 0; use `with_free` to fill them in.
+    pub fn from_memory_map(areas: &[MemoryMapArea], kernel: (PhysicalAddress, PhysicalAddress)) -> Self {
+        let (usable, reserved) = areas.iter()
+            .fold((0, 0), |(usable, reserved), area| {
+                if area.typ == MemoryAreaType::Available {
+                    // only frames that are entirely inside of the area are usable
+                    let start = (area.start + PAGE_SIZE - 1) / PAGE_SIZE;
+                    let end = area.end() / PAGE_SIZE;
+                    (usable + end.saturating_sub(start), reserved)
+                } else {
+                    (usable, reserved + (area.size + PAGE_SIZE - 1) / PAGE_SIZE)
+                }
+            });
+        let frames = |(start, end): (PhysicalAddress, PhysicalAddress)| {
+            (end + PAGE_SIZE - 1) / PAGE_SIZE - start / PAGE_SIZE
+        };
+
+        MemoryStats {
+            total: usable + reserved,
+            usable,
+            reserved,
+            kernel: frames(kernel),
+            free: 0,
+            allocated: 0,
+        }
+    }
+
+    /// Gets a copy of these statistics with the given number of free frames.
+    pub fn with_free(&self, free: usize) -> Self {
+        MemoryStats {
+            free,
+            allocated: self.usable.saturating_sub(free),
+            .. *self
+        }
+    }
+
+    pub fn print(&self) {
+        let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+        vgaprintln!("Memory: {} KiB total, {} KiB usable, {} KiB reserved",
+                    kib(self.total), kib(self.usable), kib(self.reserved));
+        vgaprintln!("        {} KiB kernel", kib(self.kernel));
+        vgaprintln!("        {} KiB free, {} KiB allocated", kib(self.free), kib(self.allocated));
+    }
+}
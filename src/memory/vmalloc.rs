@@ -0,0 +1,173 @@
+use alloc::vec::Vec;
+use memory::{
+    PAGE_SIZE, Page, PageRangeIter, ActivePageTable, EntryFlags, FrameAllocator, VirtualAddress,
+};
+
+/// The number of unmapped pages that are left in front of every region.
+const GUARD_PAGES: usize = 1;
+
+/// A page-aligned region of kernel virtual memory that was handed out by a `VirtualAllocator`.
+///
+/// Every region is preceded by an unmapped guard page, so running off the start of a region (or
+/// off the end of the region before it) faults instead of silently corrupting memory.
+#[derive(Debug)]
+pub struct VirtualRegion {
+    start: Page,
+    count: usize,
+    backed: bool,
+}
+
+impl VirtualRegion {
+    /// Gets the address that this region starts at.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start.start_address()
+    }
+
+    /// Gets the address that this region ends at (exclusive).
+    pub fn end_address(&self) -> VirtualAddress {
+        self.start_address() + self.size()
+    }
+
+    /// Gets the size of this region, in bytes.
+    pub fn size(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+
+    /// Gets the number of pages in this region.
+    pub fn page_count(&self) -> usize {
+        self.count
+    }
+
+    /// Gets the pages that make up this region.
+    pub fn pages(&self) -> PageRangeIter {
+        Page::range_inclusive(self.start, self.start + (self.count - 1))
+    }
+
+    /// Gets whether this region was backed with frames when it was allocated.
+    pub fn is_backed(&self) -> bool {
+        self.backed
+    }
+}
+
+/// An allocator for regions of kernel virtual memory.
+///
+/// Free space is kept as a sorted list of page runs, and regions are handed out first-fit.
+pub struct VirtualAllocator {
+    /// Runs of free pages, as (first page number, page count). Runs are sorted by page number and
+    /// never touch each other.
+    free: Vec<(usize, usize)>,
+}
+
+impl VirtualAllocator {
+    /// Creates a new allocator that hands out regions from the given range.
+    ///
+    /// # Arguments
+    /// `start` - the page-aligned address that the range starts at.
+    /// `end` - the page-aligned address that the range ends at (exclusive).
+    pub fn new(start: VirtualAddress, end: VirtualAddress) -> Self {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+                "Virtual allocator range {:#x} - {:#x} is not page-aligned", start, end);
+        assert!(start < end, "Virtual allocator range {:#x} - {:#x} is empty", start, end);
+        VirtualAllocator {
+            free: vec![(start / PAGE_SIZE, (end - start) / PAGE_SIZE)],
+        }
+    }
+
+    /// Reserves `count` pages of virtual memory, without mapping them.
+    pub fn alloc(&mut self, count: usize) -> Option<VirtualRegion> {
+        if count == 0 {
+            return None;
+        }
+        self.reserve(count + GUARD_PAGES)
+            .map(|number| VirtualRegion {
+                start: Page::containing_address((number + GUARD_PAGES) * PAGE_SIZE),
+                count,
+                backed: false,
+            })
+    }
+
+    /// Reserves `count` pages of virtual memory and maps each of them to a newly allocated frame.
+    ///
+    /// If the frames or page tables run out, everything that was mapped so far is undone.
+    pub fn alloc_backed<A>(&mut self, count: usize, flags: EntryFlags, active_table: &mut ActivePageTable,
+                           allocator: &mut A) -> Option<VirtualRegion>
+        where A: FrameAllocator
+    {
+        let mut region = self.alloc(count)?;
+        for (mapped, page) in region.pages().enumerate() {
+            if active_table.try_map(page, flags, allocator).is_err() {
+                for page in region.pages().take(mapped) {
+                    active_table.unmap_and_free(page, allocator);
+                }
+                self.dealloc(region, active_table, allocator);
+                return None;
+            }
+        }
+        region.backed = true;
+        Some(region)
+    }
+
+    /// Gives back a region.
+    ///
+    /// If the region was backed with frames by `alloc_backed`, its pages are unmapped and their
+    /// frames are released. Otherwise, anything that was mapped into the region must already be
+    /// unmapped.
+    pub fn dealloc<A>(&mut self, region: VirtualRegion, active_table: &mut ActivePageTable, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        if region.backed {
+            for page in region.pages() {
+                active_table.unmap_and_free(page, allocator);
+            }
+        }
+        let number = region.start_address() / PAGE_SIZE - GUARD_PAGES;
+        self.release(number, region.count + GUARD_PAGES);
+    }
+
+    /// Takes the first run of `count` free pages, returning its first page number.
+    fn reserve(&mut self, count: usize) -> Option<usize> {
+        let index = self.free.iter()
+            .position(|&(_, free)| free >= count)?;
+        let (number, free) = self.free[index];
+        if free == count {
+            self.free.remove(index);
+        } else {
+            self.free[index] = (number + count, free - count);
+        }
+        Some(number)
+    }
+
+    /// Puts a run of pages back on the free list, merging it with its neighbours.
+    fn release(&mut self, number: usize, count: usize) {
+        let index = self.free.iter()
+            .position(|&(start, _)| start > number)
+            .unwrap_or(self.free.len());
+        if index > 0 {
+            let (prev_start, prev_count) = self.free[index - 1];
+            assert!(prev_start + prev_count <= number,
+                    "Attempted to free virtual pages at {:#x} twice", number * PAGE_SIZE);
+        }
+        if index < self.free.len() {
+            assert!(number + count <= self.free[index].0,
+                    "Attempted to free virtual pages at {:#x} twice", number * PAGE_SIZE);
+        }
+
+        let merges_prev = index > 0 && {
+            let (prev_start, prev_count) = self.free[index - 1];
+            prev_start + prev_count == number
+        };
+        let merges_next = index < self.free.len() && number + count == self.free[index].0;
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                let (_, next_count) = self.free.remove(index);
+                self.free[index - 1].1 += count + next_count;
+            },
+            (true, false) => self.free[index - 1].1 += count,
+            (false, true) => {
+                let (next_start, next_count) = self.free[index];
+                self.free[index] = (next_start - count, next_count + count);
+            },
+            (false, false) => self.free.insert(index, (number, count)),
+        }
+    }
+}